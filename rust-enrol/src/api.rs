@@ -0,0 +1,748 @@
+use reqwest::header::HeaderMap;
+use reqwest::header::HeaderValue;
+use reqwest::header::AUTHORIZATION;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use tokio::sync::Mutex;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::error::EnrolError;
+use crate::http::HttpClient;
+use crate::photo::Image;
+use crate::progress::upload_bar;
+use crate::redact::{redact, redact_json};
+use crate::settings::Settings;
+
+/// full URL of the v2 API endpoint at `path`, e.g. `claim/enrol/token`, in
+/// `region` when the call overrides it, see `Settings::base_url_in`
+pub fn endpoint(config: &Settings, region: Option<&str>, path: &str) -> String {
+    format!("{}/api/v2/{}", config.base_url_in(region), path)
+}
+
+/// a claim token as returned by iProov, fields other than `token`, `pattern`
+/// and `expires_in` are kept in `other`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EnrolToken {
+    pub token: String,
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// seconds the token is valid for from when it was issued
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<u64>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+    /// when the response carrying the token arrived, from which `expires_in` counts
+    #[serde(skip)]
+    pub received_at: Option<Instant>,
+    /// the user the token was requested for
+    #[serde(skip)]
+    pub user_id: Option<String>,
+}
+
+/// how close to expiry a claim token is warned about before an image is sent with it
+const TOKEN_EXPIRY_WARNING: Duration = Duration::from_secs(10);
+
+impl EnrolToken {
+    pub fn as_str(&self) -> &str {
+        &self.token
+    }
+
+    /// the time left before the token expires, zero once it has, `None` when
+    /// iProov didn't say how long it is valid for
+    pub fn remaining(&self) -> Option<Duration> {
+        let expires_at = self.received_at? + Duration::from_secs(self.expires_in?);
+        Some(expires_at.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+}
+
+pub async fn create_token(
+    client: &HttpClient,
+    config: &Settings,
+    username: &str,
+) -> Result<EnrolToken, EnrolError> {
+    claim_token(client, config, "enrol", username).await
+}
+
+/// the claim token request as logged, with the service provider's credentials redacted
+fn claim_token_request(url: &str, body: &serde_json::Value) -> String {
+    format!(
+        "POST {} body={}",
+        url,
+        redact_json(body, &["api_key", "secret"])
+    )
+}
+
+/// requests a token for the given claim type, `enrol` or `verify`
+async fn claim_token(
+    client: &HttpClient,
+    config: &Settings,
+    claim: &str,
+    username: &str,
+) -> Result<EnrolToken, EnrolError> {
+    let url = endpoint(
+        config,
+        config.token_region.as_deref(),
+        &format!("claim/{}/token", claim),
+    );
+    let mut body = json!({
+        "resource": config.resource,
+        "api_key": config.sp_key,
+        "secret": config.sp_secret,
+        "user_id": username,
+    });
+    if let Some(assurance_type) = &config.assurance_type {
+        body["assurance_type"] = json!(assurance_type);
+    }
+    for (key, value) in &config.meta {
+        body[key.as_str()] = json!(value);
+    }
+    let msg = format!("create {} token", claim);
+    let request = claim_token_request(&url, &body);
+    if !client.should_send(&msg, &request) {
+        return Ok(EnrolToken {
+            token: format!("dry-run-{}-token", claim),
+            pattern: None,
+            expires_in: None,
+            other: serde_json::Map::new(),
+            received_at: None,
+            user_id: Some(username.to_string()),
+        });
+    }
+    let sent = client.send(&msg, |http| http.post(&url).json(&body)).await;
+    let received_at = Instant::now();
+    let token: EnrolToken = client
+        .json_response(sent, &format!("{}_token", claim), Some(username))
+        .await?;
+    Ok(EnrolToken {
+        received_at: Some(received_at),
+        user_id: Some(username.to_string()),
+        ..token
+    })
+}
+
+pub async fn send_photo(
+    client: &HttpClient,
+    config: &Settings,
+    token: &EnrolToken,
+    image: &Image,
+) -> Result<(), EnrolError> {
+    send_image(client, config, "enrol", token, image).await?;
+    Ok(())
+}
+
+/// the outcome of a verification claim, `score` is only present when iProov returns one
+#[derive(Deserialize, Serialize, Debug)]
+pub struct VerifyResult {
+    pub passed: bool,
+    #[serde(default)]
+    pub score: Option<f64>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl VerifyResult {
+    /// fails when the score is below `min`, or without a score when the
+    /// verification itself failed
+    pub fn check_min_score(&self, min: f64) -> Result<(), EnrolError> {
+        let passed = match self.score {
+            Some(score) => score >= min,
+            None => self.passed,
+        };
+        if passed {
+            Ok(())
+        } else {
+            Err(EnrolError::BelowMinScore {
+                score: self.score,
+                min,
+            })
+        }
+    }
+}
+
+/// verifies `username` against `image` using a fresh verify token
+pub async fn verify_user(
+    client: &HttpClient,
+    config: &Settings,
+    image: &Image,
+    username: &str,
+) -> Result<VerifyResult, EnrolError> {
+    let token = claim_token(client, config, "verify", username).await?;
+    let result = match send_image(client, config, "verify", &token, image).await? {
+        Some(body) => serde_json::from_slice(&body)?,
+        None => VerifyResult {
+            passed: true,
+            score: None,
+            reason: Some("dry run".to_string()),
+        },
+    };
+    info!(
+        user_id = username, passed = result.passed;
+        "user '{}' verification {}, score={:?}",
+        username,
+        if result.passed { "passed" } else { "failed" },
+        result.score
+    );
+    Ok(result)
+}
+
+/// uploads `image` against a token for the given claim type, returning the
+/// response body, or `None` in a dry run
+async fn send_image(
+    client: &HttpClient,
+    config: &Settings,
+    claim: &str,
+    token: &EnrolToken,
+    image: &Image,
+) -> Result<Option<bytes::Bytes>, EnrolError> {
+    let url = endpoint(
+        config,
+        config.token_region.as_deref(),
+        &format!("claim/{}/image", claim),
+    );
+
+    match token.remaining() {
+        Some(Duration::ZERO) => warn!(
+            "the {} token has expired and will likely be rejected, --auto-refresh-token requests a new one",
+            claim
+        ),
+        Some(remaining) if remaining < TOKEN_EXPIRY_WARNING => warn!(
+            "the {} token expires in {:.1}s",
+            claim,
+            remaining.as_secs_f64()
+        ),
+        Some(remaining) => debug!(
+            "the {} token expires in {:.0}s",
+            claim,
+            remaining.as_secs_f64()
+        ),
+        None => debug!("the {} token has no expiry", claim),
+    }
+    let msg = format!("{} image", claim);
+    let fields = image_form(config, token, image);
+    let request = fields
+        .iter()
+        .map(|(name, field)| match field {
+            FormField::Text(value) => format!("{}={}", name, value),
+            FormField::Secret(value) => format!("{}={}", name, redact(value)),
+            FormField::Image {
+                file_name,
+                mime_type,
+            } => format!(
+                "{}={} ({}, {} bytes)",
+                name,
+                file_name,
+                mime_type,
+                image.len()
+            ),
+        })
+        .collect::<Vec<_>>();
+    if !client.should_send(&msg, &format!("POST {} {}", url, request.join(" "))) {
+        return Ok(None);
+    }
+    let progress = client.upload_progress.then(|| {
+        let progress = upload_bar(image.len());
+        progress.set_message(msg.clone());
+        progress
+    });
+    let sent = client
+        .send(&msg, |http| {
+            let multipart =
+                fields
+                    .iter()
+                    .fold(
+                        reqwest::multipart::Form::new(),
+                        |form, (name, field)| match field {
+                            FormField::Text(value) | FormField::Secret(value) => {
+                                form.text(name.to_string(), value.clone())
+                            }
+                            FormField::Image {
+                                file_name,
+                                mime_type,
+                            } => form.part(
+                                name.to_string(),
+                                image
+                                    .part(progress.as_ref())
+                                    .file_name(file_name.clone())
+                                    .mime_str(mime_type)
+                                    .expect("image mime types are valid"),
+                            ),
+                        },
+                    );
+            let request = http.post(&url).multipart(multipart);
+            match client.image_timeout {
+                Some(timeout) => request.timeout(timeout),
+                None => request,
+            }
+        })
+        .await;
+    if let Some(progress) = progress {
+        progress.finish_and_clear();
+    }
+    let phase = format!("{}_image", claim);
+    client
+        .response_body(sent, &phase, token.user_id.as_deref())
+        .await
+        .map(Some)
+}
+
+/// a field of an image upload, see `image_form`
+#[derive(Debug, PartialEq)]
+pub(crate) enum FormField {
+    Text(String),
+    /// redacted when the request is logged
+    Secret(String),
+    /// the image itself, whose contents are added when the form is sent
+    Image {
+        file_name: String,
+        mime_type: &'static str,
+    },
+}
+
+/// the fields of an image upload, named as iProov's claim image endpoints
+/// expect unless `config.field_names` renames them
+pub(crate) fn image_form<'a>(
+    config: &'a Settings,
+    token: &EnrolToken,
+    image: &Image,
+) -> Vec<(&'a str, FormField)> {
+    let names = &config.field_names;
+    vec![
+        ("api_key", FormField::Secret(config.sp_key.clone())),
+        ("secret", FormField::Secret(config.sp_secret.clone())),
+        (&names.rotation, FormField::Text(image.rotation.to_string())),
+        (
+            &names.image,
+            FormField::Image {
+                file_name: format!("image.{}", image.format.extension()),
+                mime_type: image.format.mime_type(),
+            },
+        ),
+        (&names.token, FormField::Secret(token.as_str().to_string())),
+        (&names.source, FormField::Text(image.source.clone())),
+    ]
+}
+
+/// an OAuth access token and the point at which iProov stops accepting it
+#[derive(Clone, Debug)]
+pub struct AccessToken {
+    pub token: String,
+    pub expires_at: Instant,
+}
+
+/// the OAuth token response, `expires_in` is in seconds
+#[derive(Deserialize)]
+struct AccessTokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<u64>,
+    #[serde(default)]
+    token_type: Option<String>,
+}
+
+/// how long before expiry a cached access token stops being handed out
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+/// lifetime assumed for an access token when the response has no `expires_in`,
+/// short enough that the cache only shares it between nearby operations
+const DEFAULT_EXPIRES_IN: Duration = Duration::from_secs(60);
+
+/// shares one access token per region between operations, only
+/// re-authenticating once it is close to expiry
+#[derive(Default)]
+pub struct AccessTokenCache {
+    cached: Mutex<HashMap<Option<String>, AccessToken>>,
+    /// a token obtained elsewhere, handed out without ever re-authenticating
+    fixed: Option<String>,
+}
+
+impl AccessTokenCache {
+    /// a cache that always hands out `token`, never exchanging the OAuth credentials
+    pub fn fixed(token: String) -> Self {
+        Self {
+            fixed: Some(token),
+            ..Self::default()
+        }
+    }
+
+    /// the access token for calls made in `region`, or the configured region
+    /// when `None`
+    pub async fn get(
+        &self,
+        client: &HttpClient,
+        config: &Settings,
+        region: Option<&str>,
+    ) -> Result<String, EnrolError> {
+        if let Some(token) = &self.fixed {
+            return Ok(token.clone());
+        }
+        let mut cached = self.cached.lock().await;
+        let key = region.map(str::to_string);
+        if let Some(token) = cached.get(&key) {
+            if token.expires_at > Instant::now() + EXPIRY_MARGIN {
+                debug!("reusing cached oauth access token");
+                return Ok(token.token.clone());
+            }
+        }
+        let token = create_access_token(client, config, region).await?;
+        cached.insert(key, token.clone());
+        Ok(token.token)
+    }
+}
+
+/// the access token request as logged, with the key in `url`, the password
+/// and any extra OAuth fields other than `username` and `scope` redacted
+fn access_token_request(config: &Settings, url: &str) -> String {
+    let fields = config
+        .oauth_fields
+        .iter()
+        .map(|(key, value)| match key.as_str() {
+            "username" | "scope" => format!(" {}={}", key, value),
+            _ => format!(" {}={}", key, redact(value)),
+        })
+        .collect::<String>();
+    format!(
+        "POST {} grant_type={} username={} password={}{}",
+        url.replace(&config.sp_key, &redact(&config.sp_key)),
+        config.oauth_grant_type,
+        config.oa_username,
+        redact(&config.oa_pw),
+        fields
+    )
+}
+
+/// exchanges the OAuth credentials for an access token from `oauth_url`, or
+/// else the service provider's endpoint in `region` or the configured region
+pub async fn create_access_token(
+    client: &HttpClient,
+    config: &Settings,
+    region: Option<&str>,
+) -> Result<AccessToken, EnrolError> {
+    let url = match &config.oauth_url {
+        Some(url) => url.clone(),
+        None => endpoint(config, region, &format!("{}/access_token", config.sp_key)),
+    };
+
+    let mut body = HashMap::new();
+    body.insert("grant_type", config.oauth_grant_type.as_str());
+    for (key, value) in &config.oauth_fields {
+        body.insert(key, value);
+    }
+
+    let msg = "generate access token";
+    let request = access_token_request(config, &url);
+    if !client.should_send(msg, &request) {
+        return Ok(AccessToken {
+            token: "dry-run-access-token".to_string(),
+            expires_at: Instant::now() + Duration::from_secs(3600),
+        });
+    }
+    let sent = client
+        .send(msg, |http| {
+            http.post(&url)
+                .basic_auth(&config.oa_username, Some(&config.oa_pw))
+                .form(&body)
+        })
+        .await;
+
+    let requested = Instant::now();
+    // the key is part of the URL, which sending or reading the response can fail with
+    let res: AccessTokenResponse = client
+        .json_response(sent, "access_token", None)
+        .await
+        .map_err(|err| err.redact_url(&config.sp_key))?;
+    if let Some(token_type) = res
+        .token_type
+        .filter(|kind| !kind.eq_ignore_ascii_case("bearer"))
+    {
+        warn!(
+            "unexpected access token type {:?}, using it as a bearer token",
+            token_type
+        );
+    }
+    let expires_in = match res.expires_in {
+        Some(secs) => Duration::from_secs(secs),
+        None => {
+            debug!(
+                "access token response has no expires_in, assuming {}s",
+                DEFAULT_EXPIRES_IN.as_secs()
+            );
+            DEFAULT_EXPIRES_IN
+        }
+    };
+
+    if bearer(&res.access_token).is_err() {
+        return Err(EnrolError::InvalidAccessToken);
+    }
+
+    Ok(AccessToken {
+        token: res.access_token,
+        expires_at: requested + expires_in,
+    })
+}
+
+/// the `Authorization` header for `access_token`, which fails for a token with
+/// characters a header can't carry, such as a newline
+fn bearer(access_token: &str) -> Result<HeaderValue, reqwest::header::InvalidHeaderValue> {
+    HeaderValue::from_str(&format!("Bearer {}", access_token))
+}
+
+/// the status of a user once iProov has finished processing its enrolment
+pub const ENROLLED_STATUS: &str = "enrolled";
+
+/// a user as returned by `users/{user_id}`, only its status is used
+#[derive(Deserialize)]
+struct UserResponse {
+    status: String,
+}
+
+/// the status iProov reports for `username` in the region it was enrolled in,
+/// `None` while it doesn't know the user, a dry run reports every user as enrolled
+pub async fn user_status(
+    client: &HttpClient,
+    config: &Settings,
+    access_token: &str,
+    username: &str,
+) -> Result<Option<String>, EnrolError> {
+    let url = endpoint(
+        config,
+        config.token_region.as_deref(),
+        &format!("users/{}", username),
+    );
+
+    let msg = "get user status";
+    let request = format!("GET {} bearer={}", url, redact(access_token));
+    if !client.should_send(msg, &request) {
+        return Ok(Some(ENROLLED_STATUS.to_string()));
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        AUTHORIZATION,
+        bearer(access_token).expect("access tokens are checked to be valid header values"),
+    );
+
+    let sent = client
+        .send(msg, |http| http.get(&url).headers(headers.clone()))
+        .await;
+    match client
+        .json_response::<UserResponse>(sent, "user_status", Some(username))
+        .await
+    {
+        Ok(user) => Ok(Some(user.status)),
+        Err(EnrolError::Client { status, .. }) if status == StatusCode::NOT_FOUND => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+pub async fn delete_user(
+    client: &HttpClient,
+    config: &Settings,
+    access_token: &str,
+    username: &str,
+) -> Result<(), EnrolError> {
+    let url = endpoint(
+        config,
+        config.delete_region.as_deref(),
+        &format!("users/{}", username),
+    );
+
+    let msg = "delete user";
+    let request = format!("DELETE {} bearer={}", url, redact(access_token));
+    if !client.should_send(msg, &request) {
+        return Ok(());
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        AUTHORIZATION,
+        bearer(access_token).expect("access tokens are checked to be valid header values"),
+    );
+
+    let (sent, retried) = client
+        .send_retried(msg, |http| http.delete(&url).headers(headers.clone()))
+        .await;
+    match client.response_body(sent, "delete", Some(username)).await {
+        Ok(_) => info!(user_id = username; "user '{}' deleted", username),
+        // an earlier attempt that failed in transit may have deleted the user
+        Err(EnrolError::Client { status, .. }) if status == StatusCode::NOT_FOUND && retried => {
+            info!(
+                user_id = username;
+                "user '{}' not found after retrying, already deleted by an earlier attempt",
+                username
+            )
+        }
+        Err(err) => return Err(err),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::photo::{ImageData, ImageFormat};
+
+    fn config() -> Settings {
+        Settings {
+            region: "eu".to_string(),
+            img_src: "selfie".to_string(),
+            img_path: None,
+            sp_key: "key".to_string(),
+            sp_secret: "secret".to_string(),
+            oa_username: "username".to_string(),
+            oa_pw: "password".to_string(),
+            resource: "photo_enrol_test".to_string(),
+            base_url: None,
+            token_region: None,
+            delete_region: None,
+            oauth_url: None,
+            oauth_grant_type: "client_credentials".to_string(),
+            oauth_fields: Default::default(),
+            images: Vec::new(),
+            assurance_type: None,
+            meta: Default::default(),
+            field_names: Default::default(),
+            image_sources: vec!["selfie".to_string()],
+        }
+    }
+
+    #[test]
+    fn image_form_matches_the_claim_image_contract() {
+        let config = config();
+        let token = EnrolToken {
+            token: "enrol-token".to_string(),
+            pattern: None,
+            expires_in: None,
+            other: serde_json::Map::new(),
+            received_at: None,
+            user_id: None,
+        };
+        let image = Image {
+            data: ImageData::Bytes(vec![0xFF, 0xD8, 0xFF]),
+            format: ImageFormat::Jpeg,
+            rotation: 90,
+            source: "oid".to_string(),
+        };
+
+        assert_eq!(
+            image_form(&config, &token, &image),
+            vec![
+                ("api_key", FormField::Secret("key".to_string())),
+                ("secret", FormField::Secret("secret".to_string())),
+                ("rotation", FormField::Text("90".to_string())),
+                (
+                    "image",
+                    FormField::Image {
+                        file_name: "image.jpg".to_string(),
+                        mime_type: "image/jpeg",
+                    }
+                ),
+                ("token", FormField::Secret("enrol-token".to_string())),
+                ("source", FormField::Text("oid".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn endpoints_compose_onto_the_region_host() {
+        let config = config();
+        assert_eq!(
+            endpoint(&config, None, "claim/enrol/token"),
+            "https://eu.secure.iproov.me/api/v2/claim/enrol/token"
+        );
+        assert_eq!(
+            endpoint(&config, None, "claim/enrol/image"),
+            "https://eu.secure.iproov.me/api/v2/claim/enrol/image"
+        );
+        assert_eq!(
+            endpoint(&config, None, "key/access_token"),
+            "https://eu.secure.iproov.me/api/v2/key/access_token"
+        );
+        assert_eq!(
+            endpoint(&config, None, "users/test-user"),
+            "https://eu.secure.iproov.me/api/v2/users/test-user"
+        );
+        assert_eq!(
+            endpoint(&config, Some("us"), "claim/enrol/token"),
+            "https://us.secure.iproov.me/api/v2/claim/enrol/token"
+        );
+    }
+
+    #[test]
+    fn endpoints_compose_onto_the_base_url() {
+        let config = Settings {
+            base_url: Some("http://localhost:8080/".to_string()),
+            ..config()
+        };
+        assert_eq!(
+            endpoint(&config, None, "claim/enrol/token"),
+            "http://localhost:8080/api/v2/claim/enrol/token"
+        );
+        assert_eq!(
+            endpoint(&config, None, "claim/enrol/image"),
+            "http://localhost:8080/api/v2/claim/enrol/image"
+        );
+        assert_eq!(
+            endpoint(&config, None, "key/access_token"),
+            "http://localhost:8080/api/v2/key/access_token"
+        );
+        assert_eq!(
+            endpoint(&config, None, "users/test-user"),
+            "http://localhost:8080/api/v2/users/test-user"
+        );
+    }
+
+    #[test]
+    fn claim_token_request_redacts_the_credentials() {
+        let config = Settings {
+            sp_key: "sp-key-0123456789".to_string(),
+            sp_secret: "sp-secret-0123456789".to_string(),
+            ..config()
+        };
+        let body = json!({
+            "resource": config.resource,
+            "api_key": config.sp_key,
+            "secret": config.sp_secret,
+            "user_id": "test-user",
+        });
+
+        let request = claim_token_request(&endpoint(&config, None, "claim/enrol/token"), &body);
+        assert!(!request.contains(&config.sp_key));
+        assert!(!request.contains(&config.sp_secret));
+        assert!(request.contains("test-user"));
+    }
+
+    #[test]
+    fn access_token_request_redacts_the_credentials() {
+        let mut config = Settings {
+            sp_key: "sp-key-0123456789".to_string(),
+            oa_pw: "oauth-password-0123456789".to_string(),
+            ..config()
+        };
+        config.oauth_fields.insert(
+            "client_secret".to_string(),
+            "client-secret-0123456789".to_string(),
+        );
+        let url = endpoint(&config, None, &format!("{}/access_token", config.sp_key));
+
+        let request = access_token_request(&config, &url);
+        for secret in [
+            "sp-key-0123456789",
+            "oauth-password-0123456789",
+            "client-secret-0123456789",
+        ] {
+            assert!(
+                !request.contains(secret),
+                "{} leaked in {}",
+                secret,
+                request
+            );
+        }
+        assert!(request.contains("username=username"));
+    }
+}
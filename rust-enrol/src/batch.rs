@@ -0,0 +1,214 @@
+use serde::Deserialize;
+
+use std::path::Path;
+
+use crate::cli::{parse_rotation, parse_user_id, EnrolArgs, ImageArgs};
+use crate::error::EnrolError;
+use crate::settings::Settings;
+
+/// one enrolment of a `--batch-file`, fields that are left out fall back to
+/// the command line and configuration
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BatchEntry {
+    /// generated as for any other enrolment when not given
+    pub user_id: Option<String>,
+    pub img_path: String,
+    /// overrides IMAGE_SOURCE/--img-src
+    pub source: Option<String>,
+    /// overrides --rotation and the EXIF orientation
+    pub rotation: Option<u16>,
+    /// overrides --delete-user
+    pub delete: Option<bool>,
+}
+
+impl BatchEntry {
+    /// `enrol` with the entry's rotation and deletion in place of its own
+    pub fn enrol_args(&self, enrol: &EnrolArgs) -> EnrolArgs {
+        EnrolArgs {
+            delete_user: self.delete.unwrap_or(enrol.delete_user),
+            image: ImageArgs {
+                rotation: self.rotation.or(enrol.image.rotation),
+                ..enrol.image.clone()
+            },
+            ..enrol.clone()
+        }
+    }
+}
+
+/// whether any entry of the batch file at `path` deletes its user, false when
+/// it can't be read, which `read_batch` then reports
+pub fn batch_deletes(path: &Path) -> bool {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str::<Vec<BatchEntry>>(&contents).ok())
+        .is_some_and(|entries| entries.iter().any(|entry| entry.delete == Some(true)))
+}
+
+/// reads a JSON array of `BatchEntry`, validating every entry before any is
+/// enrolled, including its source against those `config` accepts, syntax
+/// errors are reported by line and invalid entries by their position in the
+/// array, counting from 1
+pub fn read_batch(path: &Path, config: &Settings) -> Result<Vec<BatchEntry>, EnrolError> {
+    let contents = std::fs::read_to_string(path).map_err(|source| EnrolError::BatchFile {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let entries: Vec<BatchEntry> =
+        serde_json::from_str(&contents).map_err(|err| EnrolError::InvalidBatchFile {
+            path: path.to_path_buf(),
+            reason: err.to_string(),
+        })?;
+    if entries.is_empty() {
+        return Err(EnrolError::InvalidBatchFile {
+            path: path.to_path_buf(),
+            reason: "no entries".to_string(),
+        });
+    }
+    for (index, entry) in entries.iter().enumerate() {
+        validate(&entries[..index], entry, config).map_err(|reason| {
+            EnrolError::InvalidBatchEntry {
+                path: path.to_path_buf(),
+                entry: index + 1,
+                reason,
+            }
+        })?;
+    }
+    Ok(entries)
+}
+
+/// checks `entry` on its own and against the `earlier` entries
+fn validate(earlier: &[BatchEntry], entry: &BatchEntry, config: &Settings) -> Result<(), String> {
+    if let Some(user_id) = &entry.user_id {
+        parse_user_id(user_id)?;
+        if let Some(other) = earlier
+            .iter()
+            .position(|other| other.user_id.as_ref() == Some(user_id))
+        {
+            return Err(format!(
+                "user id {:?} is also used by entry {}",
+                user_id,
+                other + 1
+            ));
+        }
+    }
+    let img_path = entry.img_path.as_str();
+    if img_path.is_empty() {
+        return Err("img_path must not be empty".to_string());
+    }
+    if !img_path.starts_with("http://")
+        && !img_path.starts_with("https://")
+        && !Path::new(img_path).is_file()
+    {
+        return Err(format!("image {:?} not found", img_path));
+    }
+    if let Some(source) = &entry.source {
+        config
+            .check_image_source(source)
+            .map_err(|err| err.to_string())?;
+    }
+    if let Some(rotation) = entry.rotation {
+        parse_rotation(&rotation.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(user_id: Option<&str>, img_path: &str) -> BatchEntry {
+        BatchEntry {
+            user_id: user_id.map(str::to_string),
+            img_path: img_path.to_string(),
+            source: None,
+            rotation: None,
+            delete: None,
+        }
+    }
+
+    fn config() -> Settings {
+        Settings::builder()
+            .region("eu")
+            .sp_key("key")
+            .sp_secret("secret")
+            .oa_username("username")
+            .oa_pw("password")
+            .build()
+            .unwrap()
+    }
+
+    fn write_batch(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "rust-enrol-batch-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn duplicate_user_ids_name_the_earlier_entry() {
+        let earlier = [entry(Some("alice"), "https://example.com/a.jpg")];
+        let duplicate = entry(Some("alice"), "https://example.com/b.jpg");
+
+        assert_eq!(
+            validate(&earlier, &duplicate, &config()),
+            Err("user id \"alice\" is also used by entry 1".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_entries_are_reported_by_position() {
+        let path = write_batch(
+            "rotation",
+            r#"[{"img_path": "https://example.com/a.jpg"},
+                {"img_path": "https://example.com/b.jpg", "rotation": 45}]"#,
+        );
+        let err = read_batch(&path, &config()).unwrap_err();
+        std::fs::remove_file(path).ok();
+
+        assert!(
+            matches!(err, EnrolError::InvalidBatchEntry { entry: 2, .. }),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_reported_by_line() {
+        let path = write_batch(
+            "unknown",
+            "[\n  {\"img_path\": \"https://example.com/a.jpg\"},\n  {\"img_pth\": \"b.jpg\"}\n]",
+        );
+        let err = read_batch(&path, &config()).unwrap_err();
+        std::fs::remove_file(path).ok();
+
+        assert!(
+            err.to_string().contains("unknown field `img_pth`"),
+            "{}",
+            err
+        );
+        assert!(err.to_string().contains("line 3"), "{}", err);
+    }
+
+    #[test]
+    fn entry_sources_are_checked_against_the_configured_ones() {
+        let path = write_batch(
+            "source",
+            r#"[{"img_path": "https://example.com/a.jpg", "source": "Selfie", "delete": true}]"#,
+        );
+        let err = read_batch(&path, &config()).unwrap_err();
+        let deletes = batch_deletes(&path);
+        std::fs::remove_file(path).ok();
+
+        assert!(
+            matches!(&err, EnrolError::InvalidBatchEntry { entry: 1, reason, .. }
+                if reason.contains("unknown image source \"Selfie\"")),
+            "{:?}",
+            err
+        );
+        assert!(deletes);
+    }
+}
@@ -0,0 +1,171 @@
+use serde_json::json;
+
+use std::io::IsTerminal;
+use std::net::ToSocketAddrs;
+
+use crate::api::create_access_token;
+use crate::cli::{print_json, Args, Output};
+use crate::error::{ConfigError, EnrolError, EXIT_CONFIG_ERROR, EXIT_FAILURE};
+use crate::http::{with_request_id, HttpClient};
+use crate::settings::Settings;
+
+/// the outcome of a single preflight check, `Err` holds the exit code to
+/// report and why the check failed
+type Outcome = Result<String, (u8, String)>;
+
+/// checks the configuration loads, the region's host resolves and the OAuth
+/// credentials are accepted, printing a pass/fail line per check without
+/// enrolling or deleting anything, returns the exit code of the first failed
+/// check or 0 when all passed
+pub async fn preflight(args: &Args, settings: Result<Settings, ConfigError>) -> u8 {
+    let mut report = Report::new(args);
+    let settings = match settings {
+        Ok(settings) => {
+            report.add("config", Ok("loaded".to_string()));
+            settings
+        }
+        Err(err) => {
+            report.add("config", Err((EXIT_CONFIG_ERROR, err.to_string())));
+            report.skip(&["region", "access token"]);
+            return report.exit_code;
+        }
+    };
+
+    report.add("region", check_region(args, &settings).await);
+    if report.exit_code != 0 {
+        report.skip(&["access token"]);
+        return report.exit_code;
+    }
+
+    if args.access_token.is_some() {
+        report.add(
+            "access token",
+            Ok("given with --access-token, OAuth credentials not checked".to_string()),
+        );
+        return report.exit_code;
+    }
+    let access_token = with_request_id(async {
+        let client = HttpClient::new(args)?;
+        create_access_token(&client, &settings, None).await
+    })
+    .await;
+    report.add(
+        "access token",
+        access_token
+            .map(|_| {
+                let server = settings.oauth_url.clone();
+                format!(
+                    "OAuth credentials accepted by {}",
+                    server.unwrap_or_else(|| settings.base_url())
+                )
+            })
+            .map_err(|err: EnrolError| (err.exit_code(), err.to_string())),
+    );
+    report.exit_code
+}
+
+/// validates the region unless --allow-unknown-region was given and looks up
+/// every host the endpoints are built on, that of `token_region` and
+/// `delete_region` too when they override the region
+async fn check_region(args: &Args, settings: &Settings) -> Outcome {
+    if !args.allow_unknown_region {
+        settings
+            .validate_region()
+            .map_err(|err| (EXIT_CONFIG_ERROR, err.to_string()))?;
+    }
+    let mut base_urls = vec![settings.base_url()];
+    for region in [&settings.token_region, &settings.delete_region] {
+        let base_url = settings.base_url_in(region.as_deref());
+        if !base_urls.contains(&base_url) {
+            base_urls.push(base_url);
+        }
+    }
+    let mut resolved = Vec::with_capacity(base_urls.len());
+    for base_url in &base_urls {
+        resolved.push(resolve_host(base_url).await?);
+    }
+    Ok(resolved.join(", "))
+}
+
+/// looks up the host of `base_url`, describing the address it resolves to
+async fn resolve_host(base_url: &str) -> Outcome {
+    let url = reqwest::Url::parse(base_url).map_err(|err| {
+        (
+            EXIT_CONFIG_ERROR,
+            format!("invalid URL {:?}: {}", base_url, err),
+        )
+    })?;
+    let (host, port) = match (url.host_str(), url.port_or_known_default()) {
+        (Some(host), Some(port)) => (host.to_string(), port),
+        _ => return Err((EXIT_CONFIG_ERROR, format!("no host in {:?}", base_url))),
+    };
+    let lookup = host.clone();
+    let resolved = tokio::task::spawn_blocking(move || (lookup.as_str(), port).to_socket_addrs())
+        .await
+        .expect("address lookup does not panic");
+    match resolved {
+        Ok(mut addrs) => match addrs.next() {
+            Some(addr) => Ok(format!("{} resolves to {}", host, addr.ip())),
+            None => Err((EXIT_FAILURE, format!("{} has no addresses", host))),
+        },
+        Err(err) => Err((EXIT_FAILURE, format!("could not resolve {}: {}", host, err))),
+    }
+}
+
+/// prints each check as it completes, in green or red when stdout is a terminal
+struct Report {
+    output: Output,
+    pretty: bool,
+    color: bool,
+    exit_code: u8,
+}
+
+impl Report {
+    fn new(args: &Args) -> Self {
+        Self {
+            output: args.output,
+            pretty: args.pretty,
+            color: std::io::stdout().is_terminal(),
+            exit_code: 0,
+        }
+    }
+
+    fn add(&mut self, name: &str, outcome: Outcome) {
+        let (passed, detail) = match outcome {
+            Ok(detail) => (true, detail),
+            Err((code, detail)) => {
+                if self.exit_code == 0 {
+                    self.exit_code = code;
+                }
+                (false, detail)
+            }
+        };
+        self.print(name, Some(passed), &detail);
+    }
+
+    fn skip(&self, names: &[&str]) {
+        for name in names {
+            self.print(name, None, "skipped after an earlier failure");
+        }
+    }
+
+    fn print(&self, name: &str, passed: Option<bool>, detail: &str) {
+        if self.output == Output::Json {
+            print_json(
+                &json!({ "check": name, "passed": passed, "detail": detail }),
+                self.pretty,
+            );
+            return;
+        }
+        let (label, color) = match passed {
+            Some(true) => ("PASS", "32"),
+            Some(false) => ("FAIL", "31"),
+            None => ("SKIP", "33"),
+        };
+        if self.color {
+            println!("\x1b[{}m{}\x1b[0m {}: {}", color, label, name, detail);
+        } else {
+            println!("{} {}: {}", label, name, detail);
+        }
+    }
+}
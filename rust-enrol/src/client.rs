@@ -0,0 +1,110 @@
+use crate::api::{self, AccessTokenCache, EnrolToken, VerifyResult};
+use crate::cli::{Args, ImageArgs};
+use crate::error::EnrolError;
+use crate::http::HttpClient;
+use crate::photo::Image;
+use crate::settings::Settings;
+
+use std::sync::Mutex;
+
+/// an iProov client for one configuration, owning the HTTP client and sharing
+/// an OAuth access token between the calls that need one
+pub struct IProovClient {
+    http: HttpClient,
+    config: Settings,
+    tokens: AccessTokenCache,
+    /// users enrolled through this client and not deleted since
+    enrolled: Mutex<Vec<String>>,
+}
+
+impl IProovClient {
+    /// builds the HTTP client from the timeout, proxy, TLS and user agent options
+    /// in `args`, authenticating with `args.access_token` when it is given
+    pub fn new(args: &Args, config: Settings) -> Result<Self, EnrolError> {
+        let mut client = Self::from_parts(HttpClient::new(args)?, config);
+        if let Some(token) = &args.access_token {
+            client.tokens = AccessTokenCache::fixed(token.clone());
+        }
+        Ok(client)
+    }
+
+    pub fn from_parts(http: HttpClient, config: Settings) -> Self {
+        Self {
+            http,
+            config,
+            tokens: AccessTokenCache::default(),
+            enrolled: Mutex::default(),
+        }
+    }
+
+    pub fn http(&self) -> &HttpClient {
+        &self.http
+    }
+
+    pub fn config(&self) -> &Settings {
+        &self.config
+    }
+
+    /// loads the `index`th configured image, see `Settings::image_source`, and
+    /// checks it for a face with --face-model
+    pub async fn load_image(&self, args: &ImageArgs, index: usize) -> Result<Image, EnrolError> {
+        let (path, source) = self.config.image_source(index);
+        let image = Image::load(&self.http, args, path, source).await?;
+        #[cfg(feature = "face-detection")]
+        crate::face::check(args, &image).await?;
+        Ok(image)
+    }
+
+    pub async fn enrol_token(&self, user_id: &str) -> Result<EnrolToken, EnrolError> {
+        api::create_token(&self.http, &self.config, user_id).await
+    }
+
+    pub async fn send_image(&self, token: &EnrolToken, image: &Image) -> Result<(), EnrolError> {
+        api::send_photo(&self.http, &self.config, token, image).await
+    }
+
+    pub async fn verify(&self, image: &Image, user_id: &str) -> Result<VerifyResult, EnrolError> {
+        api::verify_user(&self.http, &self.config, image, user_id).await
+    }
+
+    /// the cached access token for calls in `region`, or the configured region
+    /// when `None`, or a new one when it is close to expiry
+    pub async fn access_token(&self, region: Option<&str>) -> Result<String, EnrolError> {
+        self.tokens.get(&self.http, &self.config, region).await
+    }
+
+    /// see `api::user_status`, asked in `token_region` where the user was enrolled
+    pub async fn user_status(&self, user_id: &str) -> Result<Option<String>, EnrolError> {
+        let region = self.config.token_region.as_deref();
+        let access_token = self.access_token(region).await?;
+        api::user_status(&self.http, &self.config, &access_token, user_id).await
+    }
+
+    /// deletes `user_id` in `delete_region` when it is set, with an access token from there
+    pub async fn delete_user(&self, user_id: &str) -> Result<(), EnrolError> {
+        let region = self.config.delete_region.as_deref();
+        let access_token = self.access_token(region).await?;
+        api::delete_user(&self.http, &self.config, &access_token, user_id).await?;
+        self.enrolled
+            .lock()
+            .expect("enrolled users lock is not poisoned")
+            .retain(|enrolled| enrolled != user_id);
+        Ok(())
+    }
+
+    /// records that `user_id` was enrolled, see `enrolled`
+    pub fn record_enrolled(&self, user_id: &str) {
+        self.enrolled
+            .lock()
+            .expect("enrolled users lock is not poisoned")
+            .push(user_id.to_string());
+    }
+
+    /// the users enrolled through this client that haven't been deleted since
+    pub fn enrolled(&self) -> Vec<String> {
+        self.enrolled
+            .lock()
+            .expect("enrolled users lock is not poisoned")
+            .clone()
+    }
+}
@@ -0,0 +1,875 @@
+use futures::stream::{self, StreamExt};
+use reqwest::StatusCode;
+use serde::Serialize;
+use serde_json::json;
+use tokio_util::sync::CancellationToken;
+
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::api::{EnrolToken, VerifyResult, ENROLLED_STATUS};
+use crate::batch::read_batch;
+use crate::cli::{error_json, parse_user_id, print_json, Args, EnrolArgs, ImageArgs, Output};
+use crate::client::IProovClient;
+use crate::error::{EnrolError, EXIT_INTERRUPTED};
+use crate::http::with_request_id;
+use crate::ids::{read_ids, IdFile};
+use crate::metrics::RunMetrics;
+use crate::photo::{list_images, Image};
+use crate::progress::{self, batch_bar};
+use crate::settings::{ImageSource, Settings};
+use crate::timing::{log_summary, Timings};
+
+/// the outcome of a successful `photo_enrol`, serialized as the JSON output of an enrolment
+#[derive(Serialize, Debug)]
+pub struct EnrolOutcome {
+    pub user_id: String,
+    pub resource: String,
+    pub region: String,
+    /// the status the user was last reported with by --wait-enrolled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// the verification result, if one was run
+    #[serde(rename = "verify")]
+    pub verified: Option<VerifyResult>,
+    pub deleted: bool,
+    pub timings: Timings,
+}
+
+/// enrols `username` with `shared` or else the `index`th configured image and
+/// records it in `ids`, then optionally waits for iProov to report it as
+/// enrolled, verifies it with the same image and deletes it, timing each
+/// request, with `args.delete_user` the user is also deleted when a step after
+/// creating its token fails
+pub async fn photo_enrol(
+    iproov: &IProovClient,
+    ids: Option<&IdFile>,
+    args: &EnrolArgs,
+    shared: Option<&Image>,
+    index: usize,
+    username: &str,
+) -> Result<EnrolOutcome, EnrolError> {
+    let mut timings = Timings::default();
+    let mut token = timings.time("token", iproov.enrol_token(username)).await?;
+    let mut status = None;
+    let verified = async {
+        let loaded;
+        let image = match shared {
+            Some(image) => image,
+            None => {
+                loaded = iproov.load_image(&args.image, index).await?;
+                &loaded
+            }
+        };
+        if args.auto_refresh_token && token.is_expired() {
+            info!(user_id = username; "enrol token for user '{}' expired, requesting another", username);
+            token = timings
+                .time("token_refresh", iproov.enrol_token(username))
+                .await?;
+        }
+        timings
+            .time("image", iproov.send_image(&token, image))
+            .await?;
+        info!(user_id = username; "user '{}' enrolled", username);
+        iproov.record_enrolled(username);
+        if let Some(ids) = ids {
+            ids.record(iproov.config(), username)?;
+        }
+        if args.wait_enrolled {
+            let timeout = Duration::from_secs(args.wait_timeout);
+            let waited = timings.time("wait_enrolled", wait_enrolled(iproov, username, timeout));
+            status = Some(waited.await?);
+        }
+        if args.verify {
+            let verified = timings.time("verify", iproov.verify(image, username));
+            Ok(Some(verified.await?))
+        } else {
+            Ok(None)
+        }
+    }
+    .await;
+    let verified = match verified {
+        Ok(verified) => verified,
+        Err(err) => {
+            if args.delete_user {
+                clean_up_failed(iproov, username).await;
+            }
+            return Err(err);
+        }
+    };
+    if args.delete_user {
+        // near zero when the access token is already cached, which it then is for the delete
+        let region = iproov.config().delete_region.as_deref();
+        timings
+            .time("access_token", iproov.access_token(region))
+            .await?;
+        timings.time("delete", iproov.delete_user(username)).await?;
+    }
+    Ok(EnrolOutcome {
+        user_id: username.to_string(),
+        resource: iproov.config().resource.clone(),
+        region: iproov.config().enrol_region().to_string(),
+        status,
+        verified,
+        deleted: args.delete_user,
+        timings,
+    })
+}
+
+/// the first and longest pause between the polls of `wait_enrolled`
+const WAIT_POLL_INITIAL: Duration = Duration::from_millis(500);
+const WAIT_POLL_MAX: Duration = Duration::from_secs(5);
+
+/// polls the status of `username` until iProov reports it as enrolled,
+/// doubling the pause between polls, and fails once `timeout` has passed
+async fn wait_enrolled(
+    iproov: &IProovClient,
+    username: &str,
+    timeout: Duration,
+) -> Result<String, EnrolError> {
+    let started = Instant::now();
+    let mut delay = WAIT_POLL_INITIAL;
+    loop {
+        let status = iproov.user_status(username).await?;
+        if let Some(status) = &status {
+            if status.eq_ignore_ascii_case(ENROLLED_STATUS) {
+                info!(user_id = username; "user '{}' reported as {}", username, status);
+                return Ok(status.clone());
+            }
+        }
+        let remaining = timeout.saturating_sub(started.elapsed());
+        if remaining.is_zero() {
+            return Err(EnrolError::NotEnrolled {
+                user_id: username.to_string(),
+                waited_secs: started.elapsed().as_secs(),
+                status,
+            });
+        }
+        debug!(
+            user_id = username;
+            "user '{}' is {}, polling again in {:.1}s",
+            username,
+            status.as_deref().unwrap_or("not found"),
+            delay.min(remaining).as_secs_f64()
+        );
+        tokio::time::sleep(delay.min(remaining)).await;
+        delay = (delay * 2).min(WAIT_POLL_MAX);
+    }
+}
+
+/// deletes a user whose enrolment failed once its token was created, which may
+/// have created the user, logging whether that worked rather than failing
+async fn clean_up_failed(iproov: &IProovClient, username: &str) {
+    match iproov.delete_user(username).await {
+        Ok(()) => info!(
+            user_id = username;
+            "deleted user '{}' after its enrolment failed", username
+        ),
+        Err(EnrolError::Client { status, .. }) if status == StatusCode::NOT_FOUND => debug!(
+            user_id = username;
+            "user '{}' was never created, nothing to clean up", username
+        ),
+        Err(err) => warn!(
+            user_id = username;
+            "could not delete user '{}' after its enrolment failed: {}", username, err
+        ),
+    }
+}
+
+/// runs `photo_enrol`, printing its outcome or error and failing it when it
+/// scored below `enrol.min_score`
+async fn reported_enrol(
+    iproov: &IProovClient,
+    ids: Option<&IdFile>,
+    args: &Args,
+    enrol: &EnrolArgs,
+    shared: Option<&Image>,
+    index: usize,
+    username: &str,
+) -> Result<EnrolOutcome, EnrolError> {
+    let (result, output) = checked_enrol(iproov, ids, enrol, shared, index, username).await;
+    if args.output == Output::Json {
+        for output in &output {
+            print_json(output, args.pretty);
+        }
+    }
+    result
+}
+
+/// runs `photo_enrol`, failing it when it scored below `enrol.min_score` and
+/// logging the error, along with the JSON output of its outcome, its error or
+/// both when only the score failed
+async fn checked_enrol(
+    iproov: &IProovClient,
+    ids: Option<&IdFile>,
+    enrol: &EnrolArgs,
+    shared: Option<&Image>,
+    index: usize,
+    username: &str,
+) -> (Result<EnrolOutcome, EnrolError>, Vec<serde_json::Value>) {
+    let result = photo_enrol(iproov, ids, enrol, shared, index, username).await;
+    let mut output = Vec::new();
+    if let Ok(outcome) = &result {
+        output.push(json!(outcome));
+    }
+    // checked once the result is out, so the score is reported either way
+    let result = result.and_then(|outcome| {
+        if let (Some(min), Some(verified)) = (enrol.min_score, &outcome.verified) {
+            verified.check_min_score(min)?;
+        }
+        Ok(outcome)
+    });
+    if let Err(err) = &result {
+        let status = err.status().map(|status| status.as_u16());
+        error!(
+            user_id = username, status = status;
+            "enrolment of user '{}' failed: {}", username, err
+        );
+        let mut error = error_json(err, err.status());
+        error["user_id"] = json!(username);
+        output.push(error);
+    }
+    (result, output)
+}
+
+/// runs `photo_enrol` `enrol.count` times, or once per image in `enrol.img_dir`
+/// or entry of `enrol.batch_file`, with up to `enrol.concurrency` enrolments in
+/// flight, carrying on past individual failures unless `enrol.fail_fast` is
+/// set, and returns the errors of the failed enrolments, a single image is
+/// loaded once and shared by all of the enrolments
+pub async fn enrol_batch(
+    args: &Args,
+    enrol: &EnrolArgs,
+    config: &Settings,
+) -> Result<Vec<EnrolError>, EnrolError> {
+    let entries = match &enrol.batch_file {
+        Some(path) => Some(read_batch(path, config)?),
+        None => None,
+    };
+    // the directory's images or the batch's entries replace the configured
+    // images, one enrolment each
+    let (config, count) = match (&enrol.img_dir, &entries) {
+        (_, Some(entries)) => {
+            let images = entries
+                .iter()
+                .map(|entry| ImageSource {
+                    path: entry.img_path.clone(),
+                    source: entry.source.clone().unwrap_or(config.img_src.clone()),
+                })
+                .collect();
+            let config = Settings {
+                images,
+                ..config.clone()
+            };
+            (config, entries.len() as u32)
+        }
+        (Some(dir), None) => {
+            let images = list_images(dir)?
+                .into_iter()
+                .map(|path| ImageSource {
+                    path: path.to_string_lossy().into_owned(),
+                    source: config.img_src.clone(),
+                })
+                .collect::<Vec<_>>();
+            let count = images.len() as u32;
+            let config = Settings {
+                images,
+                ..config.clone()
+            };
+            (config, count)
+        }
+        (None, None) => (config.clone(), enrol.count),
+    };
+    // derived up front, so a bad or shared file name fails before anything is enrolled
+    let file_ids = if enrol.id_from_filename {
+        Some(filename_ids(&config.images)?)
+    } else {
+        None
+    };
+    let iproov = IProovClient::new(args, config)?;
+    let ids = match &enrol.id_file {
+        Some(path) => Some(IdFile::open(path, args.output)?),
+        None => None,
+    };
+
+    // read once rather than from disk (or a URL) for every user it is enrolled as
+    let shared = if count > 1 && iproov.config().images.is_empty() {
+        Some(iproov.load_image(&enrol.image, 0).await?.buffered().await?)
+    } else {
+        None
+    };
+
+    let interrupted = CancellationToken::new();
+    let handler = tokio::spawn(on_interrupt(interrupted.clone()));
+    let failed = CancellationToken::new();
+
+    let (iproov, ids, shared, entries) = (&iproov, ids.as_ref(), shared.as_ref(), entries.as_ref());
+    let file_ids = file_ids.as_ref();
+    let config = iproov.config();
+    let started = Instant::now();
+    // one bar across the batch, uploads don't get their own when there are several
+    let progress = (count > 1 && progress::enabled(args)).then(|| batch_bar(u64::from(count)));
+    let results = stream::iter(0..count)
+        .take_until(interrupted.cancelled())
+        // each user gets its own request id, covering its requests and log lines
+        .map(|index| {
+            with_request_id(async move {
+                let entry = entries.map(|entries| &entries[index as usize]);
+                let user_id = entry.and_then(|entry| entry.user_id.as_ref());
+                let file_id = file_ids.map(|file_ids| &file_ids[index as usize]);
+                let username = match user_id.or(enrol.user_id.as_ref()).or(file_id) {
+                    Some(user_id) => user_id.clone(),
+                    None => petname::petname(enrol.name_words, &enrol.name_separator),
+                };
+                let entry_args = entry.map(|entry| entry.enrol_args(enrol));
+                let enrol = entry_args.as_ref().unwrap_or(enrol);
+                let (result, output) =
+                    checked_enrol(iproov, ids, enrol, shared, index as usize, &username).await;
+                // a batch file's results are printed together once it completes
+                if entries.is_none() && args.output == Output::Json {
+                    for output in &output {
+                        print_json(output, args.pretty);
+                    }
+                }
+                let result = match result {
+                    Ok(outcome) => Ok((username, outcome.timings)),
+                    Err(err) => Err((username, err)),
+                };
+                (index, output, result)
+            })
+        })
+        .buffer_unordered(enrol.concurrency as usize)
+        .inspect(|(_, _, result)| {
+            if let Some(progress) = &progress {
+                progress.inc(1);
+            }
+            if enrol.fail_fast && result.is_err() {
+                failed.cancel();
+            }
+        })
+        // ending the stream drops, and so cancels, the enrolments still in flight
+        .take_until(failed.cancelled())
+        .collect::<Vec<_>>()
+        .await;
+    if let Some(progress) = progress {
+        progress.finish_and_clear();
+    }
+    let launched = results.len() as u32;
+    if entries.is_some() && args.output == Output::Json {
+        print_json(&batch_results(count, &results), args.pretty);
+    }
+    let mut enrolled = Vec::new();
+    let mut timings = Vec::new();
+    let mut failures = Vec::new();
+    for (_, _, result) in results {
+        match result {
+            Ok((username, enrolment)) => {
+                enrolled.push(username);
+                timings.push(enrolment);
+            }
+            Err(err) => failures.push(err),
+        }
+    }
+
+    if count > 1 {
+        let failed = failures.len() as u32;
+        let elapsed = started.elapsed().as_secs_f64();
+        info!(
+            "{} enrolled, {} failed in {:.1}s ({:.2} users/sec)",
+            launched - failed,
+            failed,
+            elapsed,
+            f64::from(launched) / elapsed
+        );
+        log_summary(&timings);
+        if !enrolled.is_empty() {
+            info!("enrolled users: {}", enrolled.join(", "));
+        }
+        // repeated together at the end, as the failures are easily lost among the other logs
+        for (username, err) in &failures {
+            error!(user_id = username.as_str(); "failed user '{}': {}", username, err);
+        }
+    }
+    if let Some(path) = &enrol.metrics_file {
+        let metrics = RunMetrics {
+            succeeded: u64::from(launched) - failures.len() as u64,
+            failed: failures.len() as u64,
+            duration: started.elapsed(),
+        };
+        metrics.write(path, config);
+    }
+    // the handler outlives any clean up, so a second Ctrl-C can still cut it short
+    if interrupted.is_cancelled() {
+        clean_up_stopped(iproov, enrol.delete_user, "interrupted").await;
+        handler.abort();
+        return Err(EnrolError::Interrupted {
+            started: launched,
+            count,
+        });
+    }
+    if failed.is_cancelled() {
+        warn!(
+            "stopped at the first failure with --fail-fast, {} of {} enrolments completed",
+            launched, count
+        );
+        clean_up_stopped(iproov, enrol.delete_user, "stopped").await;
+    }
+    handler.abort();
+    Ok(failures.into_iter().map(|(_, err)| err).collect())
+}
+
+/// the results of a batch file's enrolments as an array in entry order, each
+/// the output of `checked_enrol` merged into one object with its entry number
+/// counting from 1, or marked as skipped when it wasn't run or was cancelled
+fn batch_results<T>(count: u32, results: &[(u32, Vec<serde_json::Value>, T)]) -> serde_json::Value {
+    let entries = (0..count)
+        .map(|index| {
+            let mut entry = json!({ "entry": index + 1 });
+            match results.iter().find(|(ran, _, _)| *ran == index) {
+                Some((_, output, _)) => {
+                    for fields in output.iter().filter_map(serde_json::Value::as_object) {
+                        for (key, value) in fields {
+                            entry[key] = value.clone();
+                        }
+                    }
+                }
+                None => entry["skipped"] = json!(true),
+            }
+            entry
+        })
+        .collect();
+    serde_json::Value::Array(entries)
+}
+
+/// iterations of `enrol_loop` between the running totals it logs
+const LOOP_SUMMARY_EVERY: u64 = 10;
+
+/// enrols a freshly generated user every `enrol.loop_interval_secs` until
+/// Ctrl-C, or the first failure with `enrol.fail_fast`, logging running
+/// totals of the successes and failures, then cleans
+/// up as an interrupted batch does and returns the errors of the failed enrolments
+pub async fn enrol_loop(
+    args: &Args,
+    enrol: &EnrolArgs,
+    config: &Settings,
+) -> Result<Vec<EnrolError>, EnrolError> {
+    let iproov = IProovClient::new(args, config.clone())?;
+    let ids = match &enrol.id_file {
+        Some(path) => Some(IdFile::open(path, args.output)?),
+        None => None,
+    };
+    let shared = if iproov.config().images.is_empty() {
+        Some(iproov.load_image(&enrol.image, 0).await?.buffered().await?)
+    } else {
+        None
+    };
+    let interval = Duration::from_secs(enrol.loop_interval_secs);
+
+    let interrupted = CancellationToken::new();
+    let handler = tokio::spawn(on_interrupt(interrupted.clone()));
+    let started = Instant::now();
+    let mut iterations = 0;
+    let mut failures = Vec::new();
+    while !interrupted.is_cancelled() {
+        let username = petname::petname(enrol.name_words, &enrol.name_separator);
+        let index = iterations as usize;
+        let result = with_request_id(reported_enrol(
+            &iproov,
+            ids.as_ref(),
+            args,
+            enrol,
+            shared.as_ref(),
+            index,
+            &username,
+        ))
+        .await;
+        iterations += 1;
+        if let Err(err) = result {
+            failures.push(err);
+            if enrol.fail_fast {
+                warn!("stopped at the first failure with --fail-fast");
+                break;
+            }
+        }
+        if iterations % LOOP_SUMMARY_EVERY == 0 {
+            log_loop_totals(iterations, failures.len() as u64, started);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = interrupted.cancelled() => {}
+        }
+    }
+    log_loop_totals(iterations, failures.len() as u64, started);
+    if let Some(path) = &enrol.metrics_file {
+        let metrics = RunMetrics {
+            succeeded: iterations - failures.len() as u64,
+            failed: failures.len() as u64,
+            duration: started.elapsed(),
+        };
+        metrics.write(path, iproov.config());
+    }
+    let why = if interrupted.is_cancelled() {
+        "interrupted"
+    } else {
+        "stopped"
+    };
+    clean_up_stopped(&iproov, enrol.delete_user, why).await;
+    // only now, so a second Ctrl-C can still cut the clean up short
+    handler.abort();
+    Ok(failures)
+}
+
+fn log_loop_totals(iterations: u64, failed: u64, started: Instant) {
+    info!(
+        "{} iterations in {:.0}s: {} succeeded, {} failed",
+        iterations,
+        started.elapsed().as_secs_f64(),
+        iterations - failed,
+        failed
+    );
+}
+
+/// cancels `interrupted` on the first Ctrl-C so that no more enrolments are
+/// started, a second Ctrl-C exits without waiting for those in flight
+async fn on_interrupt(interrupted: CancellationToken) {
+    if tokio::signal::ctrl_c().await.is_err() {
+        return;
+    }
+    warn!("interrupted, waiting for any enrolments in flight, press Ctrl-C again to exit now");
+    interrupted.cancel();
+    if tokio::signal::ctrl_c().await.is_ok() {
+        std::process::exit(EXIT_INTERRUPTED.into());
+    }
+}
+
+/// deletes the users a batch left enrolled when `delete` is set and it was
+/// stopped early, e.g. those whose verification failed, or lists them
+/// otherwise, `why` it stopped starts each log line
+async fn clean_up_stopped(iproov: &IProovClient, delete: bool, why: &str) {
+    let enrolled = iproov.enrolled();
+    if enrolled.is_empty() {
+        info!("{}, no users were left enrolled", why);
+        return;
+    }
+    if !delete {
+        warn!(
+            "{}, {} users remain enrolled: {}",
+            why,
+            enrolled.len(),
+            enrolled.join(", ")
+        );
+        return;
+    }
+    let mut deleted = Vec::new();
+    let mut remaining = Vec::new();
+    for user_id in enrolled {
+        match with_request_id(iproov.delete_user(&user_id)).await {
+            Ok(()) => deleted.push(user_id),
+            Err(err) => {
+                error!(user_id = user_id.as_str(); "clean up of user '{}' failed: {}", user_id, err);
+                remaining.push(user_id);
+            }
+        }
+    }
+    if !deleted.is_empty() {
+        info!(
+            "{}, cleaned up {} users: {}",
+            why,
+            deleted.len(),
+            deleted.join(", ")
+        );
+    }
+    if !remaining.is_empty() {
+        warn!(
+            "{}, {} users remain enrolled: {}",
+            why,
+            remaining.len(),
+            remaining.join(", ")
+        );
+    }
+}
+
+/// the user id of each of `images` from its file name, see `filename_id`,
+/// checked with `parse_user_id` and for two files giving the same id, as
+/// `a.jpg` and `a.png` would
+fn filename_ids(images: &[ImageSource]) -> Result<Vec<String>, EnrolError> {
+    let mut user_ids: Vec<String> = Vec::with_capacity(images.len());
+    for image in images {
+        let invalid = |reason| EnrolError::InvalidFilenameId {
+            path: image.path.clone(),
+            reason,
+        };
+        let user_id = parse_user_id(&filename_id(&image.path)).map_err(invalid)?;
+        if let Some(other) = user_ids.iter().position(|other| *other == user_id) {
+            return Err(invalid(format!(
+                "user id {:?} is also derived from {:?}",
+                user_id, images[other].path
+            )));
+        }
+        user_ids.push(user_id);
+    }
+    Ok(user_ids)
+}
+
+/// a user id from the file name of `path` without its extension, with any
+/// character `parse_user_id` rejects replaced by '_'
+fn filename_id(path: &str) -> String {
+    let stem = Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    stem.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '~') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// deletes an existing user without enrolling anything first, a user that
+/// doesn't exist is only warned about and reported as not deleted
+pub async fn delete_by_id(
+    args: &Args,
+    config: &Settings,
+    user_id: &str,
+) -> Result<bool, EnrolError> {
+    let iproov = IProovClient::new(args, config.clone())?;
+    delete_existing(&iproov, args, user_id).await
+}
+
+/// deletes every user id listed in `path` (see `IdFile`), carrying on past
+/// individual failures, and returns the errors of the failed deletions
+pub async fn delete_listed(
+    args: &Args,
+    config: &Settings,
+    path: &Path,
+) -> Result<Vec<EnrolError>, EnrolError> {
+    let user_ids = read_ids(path)?;
+    info!(
+        "deleting {} users listed in {}",
+        user_ids.len(),
+        path.display()
+    );
+    let iproov = IProovClient::new(args, config.clone())?;
+    let mut failures = Vec::new();
+    for user_id in &user_ids {
+        let deleted = with_request_id(delete_existing(&iproov, args, user_id)).await;
+        if let Err(err) = deleted {
+            error!(user_id = user_id.as_str(); "deletion of user '{}' failed: {}", user_id, err);
+            if args.output == Output::Json {
+                let mut output = error_json(&err, err.status());
+                output["user_id"] = json!(user_id);
+                print_json(&output, args.pretty);
+            }
+            failures.push(err);
+        }
+    }
+    Ok(failures)
+}
+
+async fn delete_existing(
+    iproov: &IProovClient,
+    args: &Args,
+    user_id: &str,
+) -> Result<bool, EnrolError> {
+    let deleted = match iproov.delete_user(user_id).await {
+        Ok(()) => true,
+        Err(EnrolError::Client { status, .. }) if status == StatusCode::NOT_FOUND => {
+            warn!(user_id = user_id; "user '{}' never existed, nothing to delete", user_id);
+            false
+        }
+        Err(err) => return Err(err),
+    };
+    if args.output == Output::Json {
+        print_json(
+            &json!({
+                "user_id": user_id,
+                "region": iproov.config().deletion_region(),
+                "deleted": deleted,
+            }),
+            args.pretty,
+        );
+    }
+    Ok(deleted)
+}
+
+/// enrols `user_id` again with the configured image, deleting its existing
+/// enrolment first when `delete_first` is set, and reports whether it was
+/// deleted along with the new enrolment
+pub async fn reenrol_by_id(
+    args: &Args,
+    image: &ImageArgs,
+    config: &Settings,
+    user_id: &str,
+    delete_first: bool,
+) -> Result<EnrolOutcome, EnrolError> {
+    let iproov = IProovClient::new(args, config.clone())?;
+    let image = iproov.load_image(image, 0).await?;
+    let mut timings = Timings::default();
+    let deleted = if delete_first {
+        match timings.time("delete", iproov.delete_user(user_id)).await {
+            Ok(()) => true,
+            Err(EnrolError::Client { status, .. }) if status == StatusCode::NOT_FOUND => {
+                warn!(user_id = user_id; "user '{}' wasn't enrolled, enrolling it afresh", user_id);
+                false
+            }
+            Err(err) => return Err(err),
+        }
+    } else {
+        false
+    };
+    let token = timings.time("token", iproov.enrol_token(user_id)).await?;
+    timings
+        .time("image", iproov.send_image(&token, &image))
+        .await?;
+    info!(
+        user_id = user_id;
+        "user '{}' enrolled again{}",
+        user_id,
+        if deleted { " after deleting its previous enrolment" } else { "" }
+    );
+    let outcome = EnrolOutcome {
+        user_id: user_id.to_string(),
+        resource: config.resource.clone(),
+        region: config.enrol_region().to_string(),
+        status: None,
+        verified: None,
+        deleted,
+        timings,
+    };
+    if args.output == Output::Json {
+        print_json(&json!(outcome), args.pretty);
+    }
+    Ok(outcome)
+}
+
+/// verifies an existing user against the configured image, failing if it
+/// scores below `min_score`
+pub async fn verify_by_id(
+    args: &Args,
+    image: &ImageArgs,
+    config: &Settings,
+    user_id: &str,
+    min_score: Option<f64>,
+) -> Result<VerifyResult, EnrolError> {
+    let iproov = IProovClient::new(args, config.clone())?;
+    let image = iproov.load_image(image, 0).await?;
+    let result = iproov.verify(&image, user_id).await?;
+    if args.output == Output::Json {
+        print_json(
+            &json!({
+                "user_id": user_id,
+                "resource": config.resource,
+                "region": config.enrol_region(),
+                "verify": result,
+            }),
+            args.pretty,
+        );
+    }
+    if let Some(min) = min_score {
+        result.check_min_score(min)?;
+    }
+    Ok(result)
+}
+
+/// creates an enrol token for `user_id` and prints it, the token alone in text
+/// output so it can be piped, or with every field iProov returned as JSON
+pub async fn token_by_id(
+    args: &Args,
+    config: &Settings,
+    user_id: &str,
+) -> Result<EnrolToken, EnrolError> {
+    let iproov = IProovClient::new(args, config.clone())?;
+    let token = iproov.enrol_token(user_id).await?;
+    match args.output {
+        Output::Text => {
+            match token.expires_in {
+                Some(expires_in) => info!(
+                    user_id = user_id;
+                    "token created for user '{}', expires in {}s", user_id, expires_in
+                ),
+                None => info!(user_id = user_id; "token created for user '{}'", user_id),
+            }
+            println!("{}", token.as_str());
+        }
+        Output::Json => print_json(
+            &json!({
+                "user_id": user_id,
+                "resource": config.resource,
+                "region": config.enrol_region(),
+                "token": token,
+            }),
+            args.pretty,
+        ),
+    }
+    Ok(token)
+}
+
+/// asks on stderr before a command deletes users unless --yes was given, and
+/// refuses when stdin isn't a terminal to ask on, nothing is deleted in a dry run
+pub fn confirm_deletion(args: &Args, config: &Settings) -> Result<(), EnrolError> {
+    let what = match args.command.deletes() {
+        Some(what) if !args.yes && !args.dry_run => what,
+        _ => return Ok(()),
+    };
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Err(EnrolError::Unconfirmed(what));
+    }
+    eprint!(
+        "delete {} from region {} ({})? [y/N] ",
+        what,
+        config.deletion_region(),
+        config.base_url_in(config.delete_region.as_deref())
+    );
+    std::io::stderr().flush().map_err(EnrolError::Prompt)?;
+    let mut answer = String::new();
+    stdin
+        .lock()
+        .read_line(&mut answer)
+        .map_err(EnrolError::Prompt)?;
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(EnrolError::Cancelled(what)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn images(paths: &[&str]) -> Vec<ImageSource> {
+        paths
+            .iter()
+            .map(|path| ImageSource {
+                path: path.to_string(),
+                source: "selfie".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn filename_ids_replace_invalid_characters() {
+        let ids = filename_ids(&images(&["dir/alice smith.jpg", "dir/bob.png"])).unwrap();
+        assert_eq!(ids, vec!["alice_smith", "bob"]);
+    }
+
+    #[test]
+    fn filename_ids_reject_shared_and_invalid_stems() {
+        match filename_ids(&images(&["dir/a.jpg", "dir/a.png"])) {
+            Err(EnrolError::InvalidFilenameId { path, reason }) => {
+                assert_eq!(path, "dir/a.png");
+                assert!(reason.contains("dir/a.jpg"), "{}", reason);
+            }
+            other => panic!("expected a shared user id, got {:?}", other),
+        }
+        assert!(matches!(
+            filename_ids(&images(&["dir/...jpg"])),
+            Err(EnrolError::InvalidFilenameId { .. })
+        ));
+    }
+}
@@ -0,0 +1,338 @@
+use reqwest::StatusCode;
+
+use crate::redact::redact;
+
+#[derive(thiserror::Error, Debug)]
+pub enum EnrolError {
+    #[error("Client Error during {msg:?} from {url}: <{status}, {body}>{}", auth_hint(*.status, .msg))]
+    Client {
+        msg: String,
+        url: String,
+        status: StatusCode,
+        body: serde_json::Value,
+    },
+    #[error("Server Error during {msg:?} from {url}: <{status}, {body}>")]
+    Server {
+        msg: String,
+        url: String,
+        status: StatusCode,
+        body: serde_json::Value,
+    },
+    #[error("Unknown Error during {msg:?} from {url}: <{status}, {body}>")]
+    Unknown {
+        msg: String,
+        url: String,
+        status: StatusCode,
+        body: serde_json::Value,
+    },
+    #[error("request failed: {0}{}", connection_hint(.0))]
+    Request(#[source] reqwest::Error),
+    #[error("request timed out: {0}")]
+    Timeout(reqwest::Error),
+    #[error("invalid proxy {url:?}: {source}")]
+    Proxy { url: String, source: reqwest::Error },
+    #[error("could not read image: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid JSON in response: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("the access token iProov returned has characters an Authorization header can't carry")]
+    InvalidAccessToken,
+    #[error("no image configured, set IMAGE_PATH or pass --img-path")]
+    NoImage,
+    #[error("image is empty, check --img-path/IMAGE_PATH points at the right file")]
+    EmptyImage,
+    #[error(
+        "image is {size} bytes, more than the {max} allowed, shrink it or raise --max-image-bytes"
+    )]
+    ImageTooLarge { size: u64, max: u64 },
+    #[error("{url} returned more than the {max} bytes allowed, raise --max-image-bytes")]
+    DownloadTooLarge { url: String, max: u64 },
+    #[error("{0} is not downloaded in a dry run, use a local image")]
+    DryRunDownload(String),
+    #[error("could not read client certificate or key {path:?}: {source}")]
+    ClientCertFile {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid client certificate or key, the key must be PKCS#8 PEM: {0}")]
+    ClientIdentity(reqwest::Error),
+    #[error("could not read pinned certificate {path:?}: {source}")]
+    PinnedCertFile {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid pinned certificate {path:?}, expected PEM or DER: {source}")]
+    PinnedCert {
+        path: std::path::PathBuf,
+        source: reqwest::Error,
+    },
+    #[cfg(feature = "face-detection")]
+    #[error("could not read face detection model {path:?}: {source}")]
+    FaceModel {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+    #[cfg(feature = "face-detection")]
+    #[error("no face found in the image, check it is a portrait photo or drop --require-face")]
+    NoFace,
+    #[error("could not save response to {path:?}: {source}")]
+    ResponseFile {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+    #[error("could not use id file {path:?}: {source}")]
+    IdFile {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid user id on line {line} of {path:?}: {reason}")]
+    InvalidId {
+        path: std::path::PathBuf,
+        line: usize,
+        reason: String,
+    },
+    #[error("can't derive a user id from {path:?}: {reason}")]
+    InvalidFilenameId { path: String, reason: String },
+    #[error("could not read batch file {path:?}: {source}")]
+    BatchFile {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid batch file {path:?}: {reason}")]
+    InvalidBatchFile {
+        path: std::path::PathBuf,
+        reason: String,
+    },
+    #[error("invalid entry {entry} of batch file {path:?}: {reason}")]
+    InvalidBatchEntry {
+        path: std::path::PathBuf,
+        entry: usize,
+        reason: String,
+    },
+    #[error("could not read image directory {path:?}: {source}")]
+    ImageDir {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+    #[error("no JPEG, PNG or WebP images found in {0:?}")]
+    NoImagesInDir(std::path::PathBuf),
+    #[error("refusing to delete {0} without confirmation, pass --yes when stdin isn't a terminal")]
+    Unconfirmed(String),
+    #[error("deletion of {0} cancelled")]
+    Cancelled(String),
+    #[error("could not ask for confirmation: {0}")]
+    Prompt(#[source] std::io::Error),
+    #[error("could not decode --img-base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("verification {} is below the minimum score of {min}", match score {
+        Some(score) => format!("score {}", score),
+        None => "failed without a score, which".to_string(),
+    })]
+    BelowMinScore { score: Option<f64>, min: f64 },
+    #[error("could not decode image: {0}")]
+    Decode(#[from] image::ImageError),
+    #[error("unsupported image format, expected JPEG, PNG or WebP")]
+    UnknownImageFormat,
+    #[error("{url} did not return an image, content type {content_type:?}")]
+    NotAnImage { url: String, content_type: String },
+    #[error("interrupted after starting {started} of {count} enrolments")]
+    Interrupted { started: u32, count: u32 },
+    #[error("user '{user_id}' not enrolled after waiting {waited_secs}s, last status: {}", .status.as_deref().unwrap_or("not found"))]
+    NotEnrolled {
+        user_id: String,
+        waited_secs: u64,
+        status: Option<String>,
+    },
+}
+
+/// exit code for failures without a more specific code below
+pub const EXIT_FAILURE: u8 = 1;
+/// iProov rejected a request, e.g. bad credentials or an unknown user
+pub const EXIT_CLIENT_ERROR: u8 = 2;
+/// iProov or a gateway in front of it failed, usually worth retrying later
+pub const EXIT_SERVER_ERROR: u8 = 3;
+/// the configuration is missing or invalid
+pub const EXIT_CONFIG_ERROR: u8 = 4;
+/// a request timed out
+pub const EXIT_TIMEOUT: u8 = 5;
+/// a verification scored below `--min-score`
+pub const EXIT_BELOW_MIN_SCORE: u8 = 6;
+/// `--wait-enrolled` gave up before the user was reported as enrolled
+pub const EXIT_NOT_ENROLLED: u8 = 7;
+/// some of the enrolments or deletions of a batch failed, each is logged at the end of the run
+pub const EXIT_BATCH_FAILED: u8 = 8;
+/// a batch was stopped with Ctrl-C, 128 + SIGINT as shells report it
+pub const EXIT_INTERRUPTED: u8 = 130;
+
+impl EnrolError {
+    /// the process exit code for this error, see the `EXIT_` constants
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Client { .. } => EXIT_CLIENT_ERROR,
+            Self::Server { .. } => EXIT_SERVER_ERROR,
+            Self::Timeout(_) => EXIT_TIMEOUT,
+            Self::BelowMinScore { .. } => EXIT_BELOW_MIN_SCORE,
+            Self::Interrupted { .. } => EXIT_INTERRUPTED,
+            Self::NotEnrolled { .. } => EXIT_NOT_ENROLLED,
+            Self::ClientCertFile { .. }
+            | Self::ClientIdentity(_)
+            | Self::PinnedCertFile { .. }
+            | Self::PinnedCert { .. } => EXIT_CONFIG_ERROR,
+            #[cfg(feature = "face-detection")]
+            Self::FaceModel { .. } => EXIT_CONFIG_ERROR,
+            _ => EXIT_FAILURE,
+        }
+    }
+
+    /// masks `secret` wherever it appears in the URL of the failed request
+    pub(crate) fn redact_url(mut self, secret: &str) -> Self {
+        match &mut self {
+            Self::Client { url, .. } | Self::Server { url, .. } | Self::Unknown { url, .. } => {
+                *url = url.replace(secret, &redact(secret));
+            }
+            Self::Request(err) | Self::Timeout(err) => {
+                if let Some(url) = err.url_mut() {
+                    if let Ok(redacted) = url.as_str().replace(secret, &redact(secret)).parse() {
+                        *url = redacted;
+                    }
+                }
+            }
+            _ => {}
+        }
+        self
+    }
+
+    /// the HTTP status returned by iProov, if the error came from a response
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::Client { status, .. }
+            | Self::Server { status, .. }
+            | Self::Unknown { status, .. } => Some(*status),
+            Self::Request(err) | Self::Timeout(err) => err.status(),
+            _ => None,
+        }
+    }
+}
+
+/// the usual cause of a request that couldn't connect to iProov, as
+/// " (<hint>)" to append to its message, or empty when it isn't recognised
+fn connection_hint(err: &reqwest::Error) -> &'static str {
+    if !err.is_connect() {
+        return "";
+    }
+    let mut cause = std::error::Error::source(err);
+    while let Some(err) = cause {
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            if io.kind() == std::io::ErrorKind::ConnectionRefused {
+                return " (connection refused, check --proxy/HTTPS_PROXY and the network)";
+            }
+        }
+        let message = err.to_string().to_lowercase();
+        if message.contains("dns error") || message.contains("failed to lookup address") {
+            return " (the host could not be resolved, check the region code or --base-url)";
+        }
+        if ["certificate", "tls", "ssl", "handshake"]
+            .iter()
+            .any(|word| message.contains(word))
+        {
+            return " (the TLS handshake failed, check --base-url, --proxy and --client-cert)";
+        }
+        cause = err.source();
+    }
+    ""
+}
+
+/// which credentials to check when `msg` was refused as unauthorized or
+/// forbidden, as " (<hint>)" to append to its message, or empty for any other
+/// status or for a request that isn't one of iProov's calls, the claim calls
+/// are authenticated with the service provider's key and secret and the user
+/// calls with the OAuth access token
+fn auth_hint(status: StatusCode, msg: &str) -> &'static str {
+    if !matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+        return "";
+    }
+    match msg {
+        "generate access token" => {
+            " (the OAuth credentials were rejected, check OAUTH_USERNAME, OAUTH_PW and SP_KEY)"
+        }
+        "create enrol token" | "create verify token" | "enrol image" | "verify image" => {
+            " (the service provider credentials were rejected, check SP_KEY and SP_SECRET)"
+        }
+        "get user status" | "delete user" => {
+            " (the access token was rejected, check OAUTH_USERNAME and OAUTH_PW or --access-token)"
+        }
+        // e.g. downloading an image, which none of the credentials are sent to
+        _ => "",
+    }
+}
+
+impl From<reqwest::Error> for EnrolError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            Self::Timeout(err)
+        } else {
+            Self::Request(err)
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("missing configuration values: {}", .0.join(", "))]
+    Missing(Vec<String>),
+    #[error("{0} must not be empty")]
+    Empty(&'static str),
+    #[error("unknown region {region:?}, expected one of {known} (or pass --allow-unknown-region)")]
+    UnknownRegion { region: String, known: String },
+    #[error(
+        "--token-region and --delete-region pick a SaaS host, they can't be combined with base_url"
+    )]
+    RegionWithBaseUrl,
+    #[error("meta key {0:?} is reserved for the fields set by rust-enrol")]
+    ReservedMeta(String),
+    #[error(
+        "unknown image source {image_source:?}, expected one of {allowed} (or list it in image_sources)"
+    )]
+    UnknownImageSource {
+        image_source: String,
+        allowed: String,
+    },
+    #[error("unknown field {field:?} in field_names, expected one of {known}")]
+    UnknownFormField { field: String, known: String },
+    #[error("field_names sends two fields as {0:?}")]
+    DuplicateFormField(String),
+    #[error("oauth_fields can't set grant_type, use oauth_grant_type (OAUTH_GRANT_TYPE, --oauth-grant-type)")]
+    ReservedOAuthField,
+    #[error(
+        "the {grant_type} OAuth grant needs {field:?} in oauth_fields (--oauth-field {field}=...)"
+    )]
+    MissingGrantField { grant_type: String, field: String },
+    #[error("image {0:?} listed in images does not exist")]
+    MissingImage(String),
+    #[error("could not read {path:?} given by {var}: {source}")]
+    SecretFile {
+        var: String,
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("unknown profile {profile:?}, the config file defines: {known}")]
+    UnknownProfile { profile: String, known: String },
+    #[error("unknown key {key:?} in the config file{}", .suggestion.as_ref().map(|key| format!(", did you mean {:?}?", key)).unwrap_or_default())]
+    UnknownKey {
+        key: String,
+        suggestion: Option<String>,
+    },
+    #[error("could not interpolate {key:?} in the config file: {reason}")]
+    Interpolation { key: String, reason: String },
+    #[error("--profile needs a config file with [profiles.<name>] sections, pass --config")]
+    ProfileWithoutFile,
+    #[cfg(feature = "secrets-manager")]
+    #[error("could not read secret {name:?} from {provider}: {reason}")]
+    SecretsProvider {
+        provider: &'static str,
+        name: String,
+        reason: String,
+    },
+    #[error("could not load config file: {0}")]
+    File(#[from] config::ConfigError),
+}
@@ -0,0 +1,82 @@
+use rustface::ImageData as GrayImage;
+
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::cli::ImageArgs;
+use crate::error::EnrolError;
+use crate::photo::{Image, ImageData};
+
+/// longest side images are shrunk to before looking for a face, which keeps the
+/// detection quick while still finding faces filling a fraction of a portrait
+const DETECTION_DIMENSION: u32 = 800;
+
+/// looks for a face in `image` with the model given as --face-model, warning
+/// when none is found or failing with --require-face, the check is skipped
+/// without a model
+pub async fn check(args: &ImageArgs, image: &Image) -> Result<(), EnrolError> {
+    let Some(model) = args.face_model.clone() else {
+        return Ok(());
+    };
+    let data = match &image.data {
+        ImageData::Bytes(bytes) => bytes.clone(),
+        ImageData::File { path, .. } => tokio::fs::read(path).await?,
+    };
+    let rotation = image.rotation;
+    let faces = tokio::task::spawn_blocking(move || count_faces(&model, &data, rotation))
+        .await
+        .expect("face detection does not panic")?;
+    match faces {
+        0 if args.require_face => Err(EnrolError::NoFace),
+        0 => {
+            warn!("no face found in the image, iProov is likely to reject it");
+            Ok(())
+        }
+        faces => {
+            debug!("found {} face(s) in the image", faces);
+            Ok(())
+        }
+    }
+}
+
+/// the model read from --face-model, which is the same for the whole run
+static MODEL: OnceLock<rustface::Model> = OnceLock::new();
+
+/// the detection model at `path`, read on first use and shared by every
+/// image after it rather than read again for each image of a batch
+fn model(path: &Path) -> Result<&'static rustface::Model, EnrolError> {
+    if let Some(model) = MODEL.get() {
+        return Ok(model);
+    }
+    let invalid = |source| EnrolError::FaceModel {
+        path: path.to_path_buf(),
+        source,
+    };
+    let file = std::fs::File::open(path).map_err(invalid)?;
+    let model = rustface::read_model(std::io::BufReader::new(file)).map_err(invalid)?;
+    // a model read concurrently by another image is just as good
+    Ok(MODEL.get_or_init(|| model))
+}
+
+/// the number of faces found in `bytes` once rotated upright by `rotation`
+/// degrees, as the model only detects frontal faces
+fn count_faces(model_path: &Path, bytes: &[u8], rotation: u16) -> Result<usize, EnrolError> {
+    let model = model(model_path)?;
+    let image = image::load_from_memory(bytes)?;
+    let image = match rotation {
+        90 => image.rotate90(),
+        180 => image.rotate180(),
+        270 => image.rotate270(),
+        _ => image,
+    };
+    let gray = image
+        .thumbnail(DETECTION_DIMENSION, DETECTION_DIMENSION)
+        .to_luma8();
+    let mut detector = rustface::create_detector_with_model(model.clone());
+    detector.set_min_face_size(20);
+    detector.set_score_thresh(2.0);
+    detector.set_pyramid_scale_factor(0.8);
+    detector.set_slide_window_step(4, 4);
+    let faces = detector.detect(&GrayImage::new(&gray, gray.width(), gray.height()));
+    Ok(faces.len())
+}
@@ -0,0 +1,463 @@
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cli::Args;
+use crate::error::EnrolError;
+use crate::progress;
+
+/// longest non-JSON error body kept in an error, gateway error pages can be large
+const MAX_TEXT_BODY: usize = 512;
+
+/// the error body as JSON, or as a (truncated) string when a gateway or proxy
+/// answered with HTML or plain text instead
+async fn error_body(res: reqwest::Response) -> Result<serde_json::Value, EnrolError> {
+    if res.content_length() == Some(0) {
+        return Ok(serde_json::Value::Null);
+    }
+    let bytes = res.bytes().await?;
+    if bytes.is_empty() {
+        return Ok(serde_json::Value::Null);
+    }
+    match serde_json::from_slice(&bytes) {
+        Ok(body) => Ok(body),
+        Err(_) => {
+            warn!("server returned a non-JSON error body");
+            let text = String::from_utf8_lossy(&bytes);
+            let text = match text.char_indices().nth(MAX_TEXT_BODY) {
+                Some((end, _)) => format!("{}...", &text[..end]),
+                None => text.into_owned(),
+            };
+            Ok(serde_json::Value::String(text.trim().to_string()))
+        }
+    }
+}
+
+pub(crate) async fn request_log(
+    res: reqwest::Response,
+    msg: &str,
+) -> Result<reqwest::Response, EnrolError> {
+    match res.status() {
+        status if status.is_success() => {
+            info!(status = status.as_u16(); "{} succeeded", msg);
+            Ok(res)
+        }
+        status => {
+            let msg = msg.to_string();
+            let url = res.url().to_string();
+            let body = error_body(res).await?;
+            if status.is_client_error() {
+                Err(EnrolError::Client {
+                    msg,
+                    url,
+                    status,
+                    body,
+                })
+            } else if status.is_server_error() {
+                Err(EnrolError::Server {
+                    msg,
+                    url,
+                    status,
+                    body,
+                })
+            } else {
+                Err(EnrolError::Unknown {
+                    msg,
+                    url,
+                    status,
+                    body,
+                })
+            }
+        }
+    }
+}
+
+tokio::task_local! {
+    /// correlation id of the operation being run, sent as `X-Request-ID` and logged
+    static REQUEST_ID: String;
+}
+
+/// the correlation id of the current operation, if it runs under `with_request_id`
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// runs `operation` under a freshly generated correlation id, so every request
+/// and log line it makes can be tied together
+pub async fn with_request_id<F: Future>(operation: F) -> F::Output {
+    REQUEST_ID
+        .scope(uuid::Uuid::new_v4().to_string(), operation)
+        .await
+}
+
+/// statuses worth retrying because they indicate a transient server side condition
+const RETRY_STATUSES: [StatusCode; 4] = [
+    StatusCode::TOO_MANY_REQUESTS,
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+
+/// spaces requests evenly so that all of the tasks sharing it together stay
+/// under a requests-per-second limit
+pub(crate) struct RateLimiter {
+    interval: Duration,
+    /// the earliest time the next request may be sent
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(per_sec: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / per_sec),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// waits for the next free slot, claiming it before waiting so concurrent
+    /// callers queue up behind each other
+    async fn acquire(&self) {
+        let slot = {
+            let mut next = self.next.lock().await;
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+
+    /// holds back every request for `delay`, as asked by a 429's Retry-After
+    async fn pause(&self, delay: Duration) {
+        let mut next = self.next.lock().await;
+        *next = (*next).max(Instant::now() + delay);
+    }
+}
+
+/// caps the retries of every task sharing it, so a failing endpoint can't
+/// multiply a large batch into thousands of retries
+pub(crate) struct RetryBudget {
+    budget: u32,
+    remaining: AtomicU32,
+    exhausted: AtomicBool,
+}
+
+impl RetryBudget {
+    fn new(budget: u32) -> Self {
+        Self {
+            budget,
+            remaining: AtomicU32::new(budget),
+            exhausted: AtomicBool::new(false),
+        }
+    }
+
+    /// takes one retry from the budget, false once it is used up, which is
+    /// logged the first time
+    fn consume(&self) -> bool {
+        let consumed = self
+            .remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| {
+                remaining.checked_sub(1)
+            })
+            .is_ok();
+        if !consumed && !self.exhausted.swap(true, Ordering::Relaxed) {
+            warn!(
+                "retry budget of {} used up, transient failures are no longer retried",
+                self.budget
+            );
+        }
+        consumed
+    }
+}
+
+/// the shared reqwest client together with the policy for sending requests through it
+pub struct HttpClient {
+    pub(crate) inner: reqwest::Client,
+    pub(crate) max_retries: u32,
+    pub(crate) retry_budget: Option<RetryBudget>,
+    pub(crate) dry_run: bool,
+    pub(crate) limiter: Option<RateLimiter>,
+    /// whether image uploads draw a progress bar
+    pub(crate) upload_progress: bool,
+    /// where response bodies are saved for auditing
+    pub(crate) response_dir: Option<PathBuf>,
+    /// the timeout of image uploads when it differs from that of the client
+    pub(crate) image_timeout: Option<Duration>,
+}
+
+impl HttpClient {
+    pub fn new(args: &Args) -> Result<Self, EnrolError> {
+        static APP_USER_AGENT: &str =
+            concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+        let user_agent = match &args.user_agent_suffix {
+            Some(suffix) => format!("{} ({})", APP_USER_AGENT, suffix),
+            None => APP_USER_AGENT.to_string(),
+        };
+        let mut builder = reqwest::Client::builder()
+            .user_agent(user_agent)
+            .timeout(Duration::from_secs(args.timeout_secs));
+        if let Some(secs) = args.connect_timeout_secs {
+            builder = builder.connect_timeout(Duration::from_secs(secs));
+        }
+        if let (Some(cert), Some(key)) = (&args.client_cert, &args.client_key) {
+            builder = builder.identity(client_identity(cert, key)?);
+        }
+        if args.insecure {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if let Some(path) = &args.pinned_cert {
+            builder = builder
+                .add_root_certificate(pinned_cert(path)?)
+                .tls_built_in_root_certs(false);
+        }
+        if let Some(url) = &args.proxy {
+            let proxy = reqwest::Proxy::all(url).map_err(|source| EnrolError::Proxy {
+                url: redact_proxy(url),
+                source,
+            })?;
+            builder = builder.proxy(proxy);
+        }
+        Ok(Self {
+            inner: builder.build()?,
+            max_retries: args.max_retries,
+            retry_budget: args.retry_budget.map(RetryBudget::new),
+            dry_run: args.dry_run,
+            limiter: args.rate_limit.map(RateLimiter::new),
+            upload_progress: progress::enabled(args) && !args.command.enrols_many(),
+            response_dir: args.response_dir.clone(),
+            image_timeout: args.image_timeout_secs.map(Duration::from_secs),
+        })
+    }
+
+    /// reads the body of a sent request's response, saving it to --response-dir,
+    /// as is the body of an error response when the request failed with one
+    pub(crate) async fn response_body(
+        &self,
+        sent: Result<reqwest::Response, EnrolError>,
+        phase: &str,
+        user_id: Option<&str>,
+    ) -> Result<bytes::Bytes, EnrolError> {
+        match sent {
+            Ok(res) => {
+                let body = res.bytes().await?;
+                self.save_response(phase, user_id, &body)?;
+                Ok(body)
+            }
+            Err(err) => {
+                if let EnrolError::Client { body, .. }
+                | EnrolError::Server { body, .. }
+                | EnrolError::Unknown { body, .. } = &err
+                {
+                    self.save_response(phase, user_id, body.to_string().as_bytes())?;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// deserializes the body of a successful response, see `response_body`, a
+    /// missing or mistyped field is reported as [`EnrolError::Json`] naming the field
+    pub(crate) async fn json_response<T: serde::de::DeserializeOwned>(
+        &self,
+        sent: Result<reqwest::Response, EnrolError>,
+        phase: &str,
+        user_id: Option<&str>,
+    ) -> Result<T, EnrolError> {
+        let body = self.response_body(sent, phase, user_id).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// writes `body` verbatim to `{timestamp}-{phase}[-{user_id}].json` in
+    /// --response-dir, the timestamp in microseconds since the Unix epoch
+    fn save_response(
+        &self,
+        phase: &str,
+        user_id: Option<&str>,
+        body: &[u8],
+    ) -> Result<(), EnrolError> {
+        let dir = match &self.response_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        let name = match user_id {
+            Some(user_id) => format!("{}-{}-{}.json", timestamp, phase, user_id),
+            None => format!("{}-{}.json", timestamp, phase),
+        };
+        let path = dir.join(name);
+        std::fs::create_dir_all(dir)
+            .and_then(|()| std::fs::write(&path, body))
+            .map_err(|source| EnrolError::ResponseFile { path, source })
+    }
+
+    /// logs the composed `request`, returning false in a dry run where the
+    /// caller should stub the response rather than send it
+    pub(crate) fn should_send(&self, msg: &str, request: &str) -> bool {
+        if self.dry_run {
+            info!("dry run, not sending {}: {}", msg, request);
+            false
+        } else {
+            debug!("{}: {}", msg, request);
+            true
+        }
+    }
+
+    /// sends the request produced by `build`, retrying connection errors and
+    /// transient statuses, and passes the final response through `request_log`
+    pub async fn send<F>(&self, msg: &str, build: F) -> Result<reqwest::Response, EnrolError>
+    where
+        F: Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    {
+        self.send_retried(msg, build).await.0
+    }
+
+    /// like `send`, also returning whether the request was retried, in which
+    /// case an earlier attempt may have taken effect without its response arriving
+    pub(crate) async fn send_retried<F>(
+        &self,
+        msg: &str,
+        build: F,
+    ) -> (Result<reqwest::Response, EnrolError>, bool)
+    where
+        F: Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    {
+        let mut attempt = 0;
+        loop {
+            if let Some(limiter) = &self.limiter {
+                limiter.acquire().await;
+            }
+            let mut request = build(&self.inner);
+            if let Some(id) = request_id() {
+                request = request.header("X-Request-ID", id);
+            }
+            let result = request.send().await;
+            let delay = match &result {
+                Ok(res) if RETRY_STATUSES.contains(&res.status()) => {
+                    let requested = retry_after(res);
+                    if let (Some(limiter), Some(delay), StatusCode::TOO_MANY_REQUESTS) =
+                        (&self.limiter, requested, res.status())
+                    {
+                        // the limit applies to every task, not just this one
+                        limiter.pause(delay).await;
+                    }
+                    Some(requested.unwrap_or_else(|| backoff(attempt)))
+                }
+                Err(err) if err.is_connect() => Some(backoff(attempt)),
+                _ => None,
+            };
+            match delay {
+                Some(delay)
+                    if attempt < self.max_retries
+                        && self.retry_budget.as_ref().is_none_or(RetryBudget::consume) =>
+                {
+                    attempt += 1;
+                    warn!(
+                        status = result.as_ref().ok().map(|res| res.status().as_u16());
+                        "{} failed, retrying in {:.1}s ({}/{})",
+                        msg,
+                        delay.as_secs_f64(),
+                        attempt,
+                        self.max_retries
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                _ => {
+                    let res = match result {
+                        Ok(res) => request_log(res, msg).await,
+                        Err(err) => Err(err.into()),
+                    };
+                    return (res, attempt > 0);
+                }
+            }
+        }
+    }
+}
+
+/// the mutual TLS identity from a PEM certificate and PKCS#8 PEM key, as
+/// required by native-tls, the TLS backend reqwest is built with here
+fn client_identity(cert: &Path, key: &Path) -> Result<reqwest::Identity, EnrolError> {
+    let read = |path: &Path| {
+        std::fs::read(path).map_err(|source| EnrolError::ClientCertFile {
+            path: path.to_path_buf(),
+            source,
+        })
+    };
+    reqwest::Identity::from_pkcs8_pem(&read(cert)?, &read(key)?).map_err(EnrolError::ClientIdentity)
+}
+
+/// the certificate for --pinned-cert, PEM when it has a PEM header and DER otherwise
+fn pinned_cert(path: &Path) -> Result<reqwest::Certificate, EnrolError> {
+    let cert = std::fs::read(path).map_err(|source| EnrolError::PinnedCertFile {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let parsed = if cert.starts_with(b"-----BEGIN") {
+        reqwest::Certificate::from_pem(&cert)
+    } else {
+        reqwest::Certificate::from_der(&cert)
+    };
+    parsed.map_err(|source| EnrolError::PinnedCert {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// masks the password of a proxy URL so it can be shown in errors, all of the
+/// credentials when the URL can't be parsed
+fn redact_proxy(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("****"));
+            parsed.to_string()
+        }
+        Ok(parsed) => parsed.to_string(),
+        Err(_) => match url.rsplit_once('@') {
+            Some((_, host)) => format!("****@{}", host),
+            None => url.to_string(),
+        },
+    }
+}
+
+/// the longest wait before a retry, whether backing off or told to by `Retry-After`
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// exponential backoff from 500ms, capped at `MAX_RETRY_DELAY`, with the upper half randomised
+fn backoff(attempt: u32) -> Duration {
+    let ceiling = Duration::from_millis(500)
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_RETRY_DELAY);
+    ceiling / 2 + ceiling.mul_f64(rand::random::<f64>() / 2.0)
+}
+
+fn retry_after(res: &reqwest::Response) -> Option<Duration> {
+    retry_after_delay(res.headers().get(RETRY_AFTER)?.to_str().ok()?)
+}
+
+/// only the delay-seconds form of `Retry-After` is understood, dates fall back
+/// to backoff, and a delay beyond `MAX_RETRY_DELAY` is cut down to it rather
+/// than stalling the run
+fn retry_after_delay(value: &str) -> Option<Duration> {
+    let delay = Duration::from_secs(value.trim().parse().ok()?);
+    Some(delay.min(MAX_RETRY_DELAY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_is_capped() {
+        assert_eq!(retry_after_delay("2"), Some(Duration::from_secs(2)));
+        assert_eq!(retry_after_delay("86400"), Some(MAX_RETRY_DELAY));
+        assert_eq!(retry_after_delay("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+}
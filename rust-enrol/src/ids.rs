@@ -0,0 +1,92 @@
+use serde_json::json;
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::cli::{parse_user_id, Output};
+use crate::error::EnrolError;
+use crate::settings::Settings;
+
+/// a record of enrolled user ids, one per line (or a JSON object per line with
+/// `--output json`), appended to as each enrolment completes
+pub struct IdFile {
+    path: PathBuf,
+    file: Mutex<File>,
+    output: Output,
+}
+
+impl IdFile {
+    pub fn open(path: &Path, output: Output) -> Result<Self, EnrolError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|source| EnrolError::IdFile {
+                path: path.to_path_buf(),
+                source,
+            })?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            output,
+        })
+    }
+
+    /// appends `user_id`, flushing straight away so a crash mid-batch still
+    /// leaves every completed enrolment on record
+    pub fn record(&self, config: &Settings, user_id: &str) -> Result<(), EnrolError> {
+        let line = match self.output {
+            Output::Text => user_id.to_string(),
+            Output::Json => json!({
+                "user_id": user_id,
+                "resource": config.resource,
+                "region": config.enrol_region(),
+            })
+            .to_string(),
+        };
+        let mut file = self
+            .file
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        writeln!(file, "{}", line)
+            .and_then(|_| file.flush())
+            .map_err(|source| EnrolError::IdFile {
+                path: self.path.clone(),
+                source,
+            })
+    }
+}
+
+/// reads the user ids recorded by `IdFile` in either format, skipping blank lines
+pub fn read_ids(path: &Path) -> Result<Vec<String>, EnrolError> {
+    let contents = std::fs::read_to_string(path).map_err(|source| EnrolError::IdFile {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let invalid = |line: usize, reason: String| EnrolError::InvalidId {
+        path: path.to_path_buf(),
+        line,
+        reason,
+    };
+    contents
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(line, text)| {
+            let user_id = if text.starts_with('{') {
+                let record: serde_json::Value =
+                    serde_json::from_str(text).map_err(|err| invalid(line, err.to_string()))?;
+                record["user_id"]
+                    .as_str()
+                    .ok_or_else(|| invalid(line, "no user_id field".to_string()))?
+                    .to_string()
+            } else {
+                text.to_string()
+            };
+            parse_user_id(&user_id).map_err(|reason| invalid(line, reason))
+        })
+        .collect()
+}
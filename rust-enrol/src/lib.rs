@@ -0,0 +1,40 @@
+//! photo enrolment against the iProov platform: token creation, image upload
+//! and optional user deletion, as used by the `rust-enrol` binary
+
+#[macro_use]
+extern crate log;
+
+pub mod api;
+pub mod batch;
+pub mod check;
+pub mod cli;
+pub mod client;
+pub mod enrol;
+pub mod error;
+#[cfg(feature = "face-detection")]
+pub mod face;
+pub mod http;
+pub mod ids;
+pub mod logging;
+pub mod metrics;
+pub mod photo;
+pub mod progress;
+pub mod redact;
+#[cfg(feature = "secrets-manager")]
+pub mod secrets;
+pub mod settings;
+pub mod timing;
+
+pub use api::{
+    create_access_token, create_token, delete_user, send_photo, verify_user, AccessToken,
+    AccessTokenCache, EnrolToken, VerifyResult,
+};
+pub use client::IProovClient;
+pub use enrol::{
+    confirm_deletion, delete_by_id, delete_listed, enrol_batch, enrol_loop, photo_enrol,
+    reenrol_by_id, token_by_id, verify_by_id, EnrolOutcome,
+};
+pub use error::{ConfigError, EnrolError};
+pub use http::HttpClient;
+pub use photo::{Image, ImageData, ImageFormat};
+pub use settings::{FieldNames, ImageSource, PartialSettings, Settings};
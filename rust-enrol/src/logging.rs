@@ -0,0 +1,68 @@
+use log::kv::{Error, Key, Value, VisitSource};
+use log::LevelFilter;
+use pretty_env_logger::env_logger::{Builder, Env};
+use serde_json::json;
+
+use std::io::Write;
+
+use crate::cli::LogFormat;
+use crate::http::request_id;
+
+/// sets up the global logger, filtered by `LOG_LEVEL` (defaults to info) unless
+/// `level` is given, lines logged during an operation carry its request id
+pub fn init(format: LogFormat, level: Option<LevelFilter>) {
+    let mut builder = Builder::from_env(Env::default().filter_or("LOG_LEVEL", "info"));
+    match level {
+        // debug logs of reqwest and hyper would drown out this crate's
+        Some(LevelFilter::Debug) => {
+            builder.filter_module(env!("CARGO_CRATE_NAME"), LevelFilter::Debug);
+        }
+        Some(level) => {
+            builder.filter_level(level);
+        }
+        None => (),
+    }
+    match format {
+        LogFormat::Text => builder.format(|buf, record| {
+            let level = buf.default_level_style(record.level());
+            write!(
+                buf,
+                "[{} {:<5} {}",
+                buf.timestamp(),
+                level.value(record.level()),
+                record.target()
+            )?;
+            if let Some(id) = request_id() {
+                write!(buf, " {}", id)?;
+            }
+            writeln!(buf, "] {}", record.args())
+        }),
+        LogFormat::Json => builder.format(|buf, record| {
+            let mut line = json!({
+                "timestamp": buf.timestamp_millis().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            if let Some(fields) = line.as_object_mut() {
+                if let Some(id) = request_id() {
+                    fields.insert("request_id".to_string(), json!(id));
+                }
+                record.key_values().visit(&mut Fields(fields)).ok();
+            }
+            writeln!(buf, "{}", line)
+        }),
+    };
+    builder.init();
+}
+
+/// copies the key-values of a log record into the JSON line as JSON values
+struct Fields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let value = serde_json::to_value(&value).unwrap_or_else(|_| json!(value.to_string()));
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
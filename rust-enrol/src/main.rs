@@ -1,186 +1,213 @@
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use serde_json::json;
 
-use pretty_env_logger;
-use pretty_env_logger::env_logger::Env;
 #[macro_use]
 extern crate log;
 
-use reqwest;
-use reqwest::header::HeaderMap;
-use reqwest::header::AUTHORIZATION;
-use reqwest::StatusCode;
-use serde::Deserialize;
-use serde_json::json;
-
-use std::collections::HashMap;
-use std::fs;
+use rust_enrol::cli::{error_json, print_json, Args, Command, Output};
+use rust_enrol::error::{EXIT_BATCH_FAILED, EXIT_CONFIG_ERROR};
+use rust_enrol::http::with_request_id;
+use rust_enrol::settings::KNOWN_REGIONS;
+use rust_enrol::{
+    check, confirm_deletion, delete_by_id, delete_listed, enrol_batch, enrol_loop, logging,
+    reenrol_by_id, token_by_id, verify_by_id, ConfigError, EnrolError, PartialSettings, Settings,
+};
 
-#[derive(Deserialize, Debug)]
-struct Settings {
-    region: String,
-    img_src: String,
-    img_path: String,
-    sp_key: String,
-    sp_secret: String,
-    oa_username: String,
-    oa_pw: String,
-}
+use std::process::ExitCode;
 
-impl Settings {
-    fn from_env() -> Self {
-        dotenv::dotenv().ok();
-        Self {
-            region: std::env::var("REGION").unwrap(),
-            img_src: std::env::var("IMAGE_SOURCE").unwrap(),
-            img_path: std::env::var("IMAGE_PATH").unwrap(),
-            sp_key: std::env::var("SP_KEY").unwrap(),
-            sp_secret: std::env::var("SP_SECRET").unwrap(),
-            oa_username: std::env::var("OAUTH_USERNAME").unwrap(),
-            oa_pw: std::env::var("OAUTH_PW").unwrap(),
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    if let Command::Enrol(enrol) = &args.command {
+        if enrol.user_id.is_some() && enrol.count > 1 {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--user-id can only be used with a --count of 1",
+                )
+                .exit();
         }
     }
-}
-
-fn request_log(res: reqwest::blocking::Response, msg: &str) -> reqwest::blocking::Response {
-    match res.status() {
-        StatusCode::OK => {
-            info!("{} succeeded", msg);
-            res
+    if let Command::Regions = args.command {
+        print_regions(&args);
+        return ExitCode::SUCCESS;
+    }
+    if let Command::Completions { shell } = args.command {
+        clap_complete::generate(
+            shell,
+            &mut Args::command(),
+            env!("CARGO_PKG_NAME"),
+            &mut std::io::stdout(),
+        );
+        return ExitCode::SUCCESS;
+    }
+    dotenv::dotenv().ok();
+    logging::init(args.log_format, args.log_level());
+    if args.dry_run {
+        warn!("dry run, requests are logged but not sent");
+    }
+    if !cfg!(feature = "face-detection")
+        && args.command.image().is_some_and(|image| image.require_face)
+    {
+        warn!("--require-face is ignored, this build doesn't have the face-detection feature");
+    }
+    if args.insecure {
+        warn!("TLS certificate verification is DISABLED, do not use --insecure against production");
+    }
+    let mut overrides = PartialSettings {
+        img_path: args
+            .command
+            .image()
+            .and_then(|image| image.img_path.clone()),
+        img_src: args.command.image().and_then(|image| image.img_src.clone()),
+        resource: args.resource.clone(),
+        assurance_type: args.assurance_type.clone(),
+        meta: (!args.meta.is_empty()).then(|| args.meta.iter().cloned().collect()),
+        base_url: args.base_url.clone(),
+        token_region: args.token_region.clone(),
+        delete_region: args.delete_region.clone(),
+        oauth_url: args.oauth_url.clone(),
+        oauth_grant_type: args.oauth_grant_type.clone(),
+        oauth_fields: (!args.oauth_fields.is_empty())
+            .then(|| args.oauth_fields.iter().cloned().collect()),
+        ..Default::default()
+    };
+    // an explicit --img-path or --img-base64 replaces any list of images from the config file
+    if overrides.img_path.is_some()
+        || args
+            .command
+            .image()
+            .is_some_and(|image| image.img_base64.is_some())
+    {
+        overrides.images = Some(Vec::new());
+    }
+    let settings = fetch_secrets(&args, &mut overrides)
+        .and_then(|()| Settings::load(args.config.as_deref(), args.profile.as_deref(), overrides));
+    if let Command::Check = args.command {
+        return ExitCode::from(check::preflight(&args, settings).await);
+    }
+    let settings = settings.and_then(|settings| {
+        if !args.allow_unknown_region {
+            settings.validate_region()?;
+        }
+        Ok(settings)
+    });
+    let settings = match settings {
+        Ok(settings) => settings,
+        Err(err) => {
+            error!("{}", err);
+            if args.output == Output::Json {
+                print_json(&error_json(err, None), args.pretty);
+            }
+            return ExitCode::from(EXIT_CONFIG_ERROR);
         }
-        status => {
-            let err: serde_json::Value = res.json().unwrap();
-            if status.is_client_error() {
-                error!("Client Error during {:?}: <{}, {}>", msg, status, err);
-                std::process::exit(1);
-            } else if status.is_server_error() {
-                error!("Server Error during {:?}: <{}, {}>", msg, status, err);
-                std::process::exit(1);
-            } else {
-                error!("Unknown Error during {:?}: <{}, {}>", msg, status, err);
-                std::process::exit(1);
+    };
+
+    let result = match confirm_deletion(&args, &settings) {
+        Ok(()) => run(&args, &settings).await,
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(failures) => failures_exit_code(&args.command, &failures),
+        Err(err) => {
+            error!(status = err.status().map(|status| status.as_u16()); "{}", err);
+            if args.output == Output::Json {
+                print_json(&error_json(&err, err.status()), args.pretty);
             }
+            ExitCode::from(err.exit_code())
         }
     }
 }
 
-fn create_token(client: &reqwest::blocking::Client, config: &Settings, username: &str) -> String {
-    let url = format!(
-        "https://{}.secure.iproov.me/api/v2/claim/enrol/token",
-        config.region
-    );
-    let body = json!({
-        "resource": "photo_enrol_test",
-        "api_key": config.sp_key,
-        "secret": config.sp_secret,
-        "user_id": username,
-    });
-    debug!("getting enrol token, url={}, body={:?}", url, body);
-    let res = client.post(&url).json(&body).send().unwrap();
-    let res: serde_json::Value = request_log(res, "create token").json().unwrap();
-    res["token"].as_str().unwrap().to_string()
-}
-
-fn send_photo(client: &reqwest::blocking::Client, config: &Settings, token: &str) {
-    let image = fs::read(&config.img_path).unwrap();
-
-    let enrol_image_url = format!(
-        "https://{}.secure.iproov.me/api/v2/claim/enrol/image",
-        config.region
-    );
-
-    let multipart = reqwest::blocking::multipart::Form::new()
-        .text("api_key", config.sp_key.clone())
-        .text("secret", config.sp_secret.clone())
-        .text("rotation", "0".to_string())
-        .part(
-            "image",
-            reqwest::blocking::multipart::Part::bytes(image).file_name("image.jpg"),
-        )
-        .text("token", token.to_string())
-        .text("source", config.img_src.clone());
-
-    debug!("sending image for enrolment, url={}", enrol_image_url);
-    let res = client
-        .post(&enrol_image_url)
-        .multipart(multipart)
-        .send()
-        .unwrap();
-    request_log(res, "enrol image");
+/// runs the subcommand, returning the failures of a batch that carried on past them
+async fn run(args: &Args, settings: &Settings) -> Result<Vec<EnrolError>, EnrolError> {
+    match &args.command {
+        Command::Enrol(enrol) => {
+            if enrol.repeat {
+                return enrol_loop(args, enrol, settings).await;
+            }
+            enrol_batch(args, enrol, settings).await
+        }
+        Command::Delete {
+            user_id: Some(user_id),
+            ..
+        } => with_request_id(delete_by_id(args, settings, user_id))
+            .await
+            .map(|_| Vec::new()),
+        Command::Delete { from_file, .. } => {
+            let path = from_file
+                .as_deref()
+                .expect("clap requires a user id or --from-file");
+            delete_listed(args, settings, path).await
+        }
+        Command::Verify {
+            user_id,
+            image,
+            min_score,
+        } => with_request_id(verify_by_id(args, image, settings, user_id, *min_score))
+            .await
+            .map(|_| Vec::new()),
+        Command::Reenrol {
+            user_id,
+            delete_first,
+            image,
+        } => with_request_id(reenrol_by_id(args, image, settings, user_id, *delete_first))
+            .await
+            .map(|_| Vec::new()),
+        Command::Token { user_id } => {
+            let user_id = user_id.clone().unwrap_or_else(|| petname::petname(5, "_"));
+            with_request_id(token_by_id(args, settings, &user_id))
+                .await
+                .map(|_| Vec::new())
+        }
+        Command::Regions | Command::Check | Command::Completions { .. } => {
+            unreachable!("regions, checks and completions run before settings are validated")
+        }
+    }
 }
 
-fn create_access_token(client: &reqwest::blocking::Client, config: &Settings) -> String {
-    let url = format!(
-        "https://{}.secure.iproov.me/api/v2/{}/access_token",
-        config.region, config.sp_key
-    );
-
-    let mut body = HashMap::new();
-    body.insert("grant_type", "client_credentials");
-
-    debug!("getting oauth access token");
-    let res = client
-        .post(&url)
-        .basic_auth(&config.oa_username, Some(&config.oa_pw))
-        .form(&body)
-        .send()
-        .unwrap();
-
-    let json: serde_json::Value = request_log(res, "generate access token").json().unwrap();
-
-    json["access_token"].as_str().unwrap().to_string()
+/// reads the secrets named with --secrets-provider into `overrides`
+#[cfg(feature = "secrets-manager")]
+fn fetch_secrets(args: &Args, overrides: &mut PartialSettings) -> Result<(), ConfigError> {
+    rust_enrol::secrets::fetch_secrets(&args.secrets, overrides)
 }
 
-fn delete_user(
-    client: &reqwest::blocking::Client,
-    config: &Settings,
-    access_token: &str,
-    username: &str,
-) {
-    let url = format!(
-        "https://{}.secure.iproov.me/api/v2/users/{}",
-        config.region, username
-    );
-
-    debug!("deleting user");
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        AUTHORIZATION,
-        format!("Bearer {}", access_token).parse().unwrap(),
-    );
-
-    let res = client.delete(&url).headers(headers).send().unwrap();
-    request_log(res, "delete user");
-    info!("user '{}' deleted", &username)
+/// secrets are only read from the environment and config file in builds
+/// without the secrets-manager feature
+#[cfg(not(feature = "secrets-manager"))]
+fn fetch_secrets(_: &Args, _: &mut PartialSettings) -> Result<(), ConfigError> {
+    Ok(())
 }
 
-fn photo_enrol(args: &Args, config: &Settings) {
-    let username = petname::petname(5, "_");
-    static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
-    let client = reqwest::blocking::Client::builder()
-        .user_agent(APP_USER_AGENT)
-        .build()
-        .unwrap();
-    let token = create_token(&client, &config, &username);
-    send_photo(&client, &config, &token);
-    info!("user '{}' enrolled", &username);
-    if args.delete_user {
-        let access_token = create_access_token(&client, &config);
-        delete_user(&client, &config, &access_token, &username);
+/// prints `KNOWN_REGIONS`, the same list regions are validated against
+fn print_regions(args: &Args) {
+    for (code, description) in KNOWN_REGIONS {
+        match args.output {
+            Output::Text => println!("{:<6} {}", code, description),
+            Output::Json => print_json(
+                &json!({ "code": code, "description": description }),
+                args.pretty,
+            ),
+        }
     }
 }
 
-/// simple program to photo enrol
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    #[arg(short, long)]
-    /// deletes the user after enrolment
-    delete_user: bool,
-}
-
-fn main() {
-    let args = Args::parse();
-    let settings = Settings::from_env();
-    pretty_env_logger::env_logger::init_from_env(Env::default().filter_or("LOG_LEVEL", "info"));
-    photo_enrol(&args, &settings);
+/// `EXIT_BATCH_FAILED` when any item of a batch failed, so CI can tell it
+/// apart from a run that failed outright, or the exit code of a single
+/// enrolment's failure
+fn failures_exit_code(command: &Command, failures: &[EnrolError]) -> ExitCode {
+    let batch = command.enrols_many()
+        || matches!(
+            command,
+            Command::Delete {
+                from_file: Some(_),
+                ..
+            }
+        );
+    match failures.first() {
+        None => ExitCode::SUCCESS,
+        Some(_) if batch => ExitCode::from(EXIT_BATCH_FAILED),
+        Some(failure) => ExitCode::from(failure.exit_code()),
+    }
 }
@@ -0,0 +1,104 @@
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::settings::Settings;
+
+/// the outcome of an enrolment run, written with --metrics-file
+pub struct RunMetrics {
+    pub succeeded: u64,
+    pub failed: u64,
+    pub duration: Duration,
+}
+
+impl RunMetrics {
+    /// the metrics in Prometheus' text exposition format, labelled with the
+    /// region enrolled in and the resource of `config`
+    pub fn render(&self, config: &Settings) -> String {
+        let labels = format!(
+            "{{region=\"{}\",resource=\"{}\"}}",
+            escape_label(config.enrol_region()),
+            escape_label(&config.resource)
+        );
+        let metrics = [
+            (
+                "enrol_success_total",
+                "counter",
+                "Enrolments that succeeded in the last run.",
+                self.succeeded.to_string(),
+            ),
+            (
+                "enrol_failure_total",
+                "counter",
+                "Enrolments that failed in the last run.",
+                self.failed.to_string(),
+            ),
+            (
+                "enrol_duration_seconds",
+                "gauge",
+                "How long the last run took.",
+                format!("{:.3}", self.duration.as_secs_f64()),
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            let _ = writeln!(text, "{}{} {}", name, labels, value);
+        }
+        text
+    }
+
+    /// writes the metrics to `path` by renaming a temporary file over it, so
+    /// node_exporter's textfile collector never reads a partly written file,
+    /// failures are logged rather than failing a run that already completed
+    pub fn write(&self, path: &Path, config: &Settings) {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let written = std::fs::write(&temporary, self.render(config))
+            .and_then(|()| std::fs::rename(&temporary, path));
+        match written {
+            Ok(()) => debug!("metrics written to {:?}", path),
+            Err(err) => error!("could not write metrics to {:?}: {}", path, err),
+        }
+    }
+}
+
+/// escapes a label value as the exposition format requires
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_labelled_prometheus_text() {
+        let config = Settings::builder()
+            .region("eu")
+            .token_region("us")
+            .resource("qa \"nightly\"")
+            .sp_key("key")
+            .sp_secret("secret")
+            .oa_username("username")
+            .oa_pw("password")
+            .build()
+            .unwrap();
+        let metrics = RunMetrics {
+            succeeded: 9,
+            failed: 1,
+            duration: Duration::from_millis(12_345),
+        };
+
+        let text = metrics.render(&config);
+        let labels = r#"{region="us",resource="qa \"nightly\""}"#;
+        assert!(text.contains("# TYPE enrol_success_total counter\n"));
+        assert!(text.contains(&format!("enrol_success_total{} 9\n", labels)));
+        assert!(text.contains(&format!("enrol_failure_total{} 1\n", labels)));
+        assert!(text.contains(&format!("enrol_duration_seconds{} 12.345\n", labels)));
+    }
+}
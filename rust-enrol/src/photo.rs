@@ -0,0 +1,512 @@
+use base64::Engine;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use indicatif::ProgressBar;
+use reqwest::header::CONTENT_TYPE;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::ReaderStream;
+
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::cli::ImageArgs;
+use crate::error::EnrolError;
+use crate::http::HttpClient;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl ImageFormat {
+    /// identifies the format from the leading magic bytes
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(Self::Jpeg)
+        } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Self::Png)
+        } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+            Some(Self::Webp)
+        } else {
+            None
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::Webp => "webp",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Webp => "image/webp",
+        }
+    }
+}
+
+/// bytes per chunk of an in-memory image uploaded with a progress bar
+const UPLOAD_CHUNK: usize = 64 * 1024;
+
+/// the contents of an image, only held in memory when they can't be re-read
+#[derive(Debug)]
+pub enum ImageData {
+    /// read from stdin, a URL or base64, or re-encoded by downscaling
+    Bytes(Vec<u8>),
+    /// a file on disk, streamed into each upload rather than buffered
+    File { path: PathBuf, len: u64 },
+}
+
+/// an image ready for upload, along with the format, rotation and source it is sent with
+pub struct Image {
+    pub data: ImageData,
+    pub format: ImageFormat,
+    pub rotation: u16,
+    pub source: String,
+}
+
+impl Image {
+    /// decodes `args.img_base64` or reads the image at `path` (see `read_image`),
+    /// uses `args.rotation` or falls back to the EXIF orientation, strips its
+    /// metadata with `args.strip_metadata` and downscales it to `args.max_dimension`,
+    /// files on disk are only read in full when they need either
+    pub async fn load(
+        client: &HttpClient,
+        args: &ImageArgs,
+        path: Option<&str>,
+        source: &str,
+    ) -> Result<Self, EnrolError> {
+        let mut bytes = match (&args.img_base64, path) {
+            (Some(data), _) => decode_base64(data)?,
+            (None, Some(path)) if is_local(path) => {
+                if !args.strip_metadata {
+                    if let Some(image) = Self::from_file(args, Path::new(path), source)? {
+                        return Ok(image);
+                    }
+                }
+                tokio::fs::read(path).await?
+            }
+            (None, path) => read_image(client, args, path.ok_or(EnrolError::NoImage)?).await?,
+        };
+        // the rotation is taken from the original since re-encoding drops the EXIF
+        // data, the pixels themselves are never rotated so it still applies
+        let mut rotation = match args.rotation {
+            Some(rotation) => rotation,
+            None => {
+                let detected = exif_rotation(&bytes);
+                debug!("exif rotation: {:?}", detected);
+                detected.unwrap_or(0)
+            }
+        };
+        if args.strip_metadata {
+            bytes = strip_metadata(&bytes, rotation)?;
+            rotation = 0;
+        }
+        if let Some(max) = args.max_dimension {
+            if let Some(resized) = downscale(&bytes, max)? {
+                bytes = resized;
+            }
+        }
+        let format = validate(&bytes, args.max_image_bytes)?;
+        Ok(Self {
+            data: ImageData::Bytes(bytes),
+            format,
+            rotation,
+            source: source.to_string(),
+        })
+    }
+
+    /// validates the file at `path` from its metadata and headers without
+    /// reading it all, `None` if it has to be downscaled and so read into memory
+    fn from_file(args: &ImageArgs, path: &Path, source: &str) -> Result<Option<Self>, EnrolError> {
+        let len = std::fs::metadata(path)?.len();
+        // downscaling may bring it under the limit, `validate` checks it afterwards
+        if args.max_dimension.is_some() && len > args.max_image_bytes {
+            return Ok(None);
+        }
+        check_size(len, args.max_image_bytes)?;
+        let mut magic = Vec::with_capacity(12);
+        std::fs::File::open(path)?
+            .take(12)
+            .read_to_end(&mut magic)?;
+        let format = ImageFormat::detect(&magic).ok_or(EnrolError::UnknownImageFormat)?;
+        let dimensions = image::io::Reader::open(path)
+            .ok()
+            .and_then(|reader| reader.with_guessed_format().ok())
+            .and_then(|reader| reader.into_dimensions().ok());
+        if let (Some(max), Some((width, height))) = (args.max_dimension, dimensions) {
+            if width.max(height) > max {
+                return Ok(None);
+            }
+        }
+        check_dimensions(dimensions);
+        let rotation = match args.rotation {
+            Some(rotation) => rotation,
+            None => {
+                let file = BufReader::new(std::fs::File::open(path)?);
+                let detected = exif_rotation_from(file);
+                debug!("exif rotation: {:?}", detected);
+                detected.unwrap_or(0)
+            }
+        };
+        Ok(Some(Self {
+            data: ImageData::File {
+                path: path.to_path_buf(),
+                len,
+            },
+            format,
+            rotation,
+            source: source.to_string(),
+        }))
+    }
+
+    /// the image with a file's contents read into memory, so it can be shared
+    /// by many uploads without reading the file for each of them
+    pub async fn buffered(self) -> Result<Self, EnrolError> {
+        let data = match self.data {
+            ImageData::File { path, .. } => ImageData::Bytes(tokio::fs::read(path).await?),
+            data => data,
+        };
+        Ok(Self { data, ..self })
+    }
+
+    /// size of the image in bytes as uploaded
+    pub fn len(&self) -> u64 {
+        match &self.data {
+            ImageData::Bytes(bytes) => bytes.len() as u64,
+            ImageData::File { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the multipart part for one upload attempt, without a file name or content
+    /// type, a file is opened afresh each time so that retries resend it from
+    /// the start, advancing `progress` from the start too as it is sent
+    pub(crate) fn part(&self, progress: Option<&ProgressBar>) -> reqwest::multipart::Part {
+        let progress = match progress {
+            Some(progress) => progress.clone(),
+            None => match &self.data {
+                ImageData::Bytes(bytes) => return reqwest::multipart::Part::bytes(bytes.clone()),
+                ImageData::File { .. } => ProgressBar::hidden(),
+            },
+        };
+        progress.set_position(0);
+        let chunks = match &self.data {
+            ImageData::Bytes(bytes) => {
+                let bytes = bytes::Bytes::from(bytes.clone());
+                let chunks = (0..bytes.len())
+                    .step_by(UPLOAD_CHUNK)
+                    .map(|start| Ok(bytes.slice(start..bytes.len().min(start + UPLOAD_CHUNK))))
+                    .collect::<Vec<std::io::Result<_>>>();
+                stream::iter(chunks).left_stream()
+            }
+            ImageData::File { path, .. } => stream::once(tokio::fs::File::open(path.clone()))
+                .map_ok(ReaderStream::new)
+                .try_flatten()
+                .right_stream(),
+        };
+        let chunks = chunks.inspect_ok(move |chunk| progress.inc(chunk.len() as u64));
+        reqwest::multipart::Part::stream_with_length(reqwest::Body::wrap_stream(chunks), self.len())
+    }
+}
+
+/// whether `path` names a file on disk rather than stdin or a URL
+fn is_local(path: &str) -> bool {
+    path != "-" && !path.starts_with("http://") && !path.starts_with("https://")
+}
+
+fn dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    image::io::Reader::new(std::io::Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// resizes an image with a side longer than `max` to fit within `max`x`max`,
+/// keeping the aspect ratio, returning it as a JPEG or `None` if it already fits
+pub fn downscale(bytes: &[u8], max: u32) -> Result<Option<Vec<u8>>, EnrolError> {
+    match dimensions(bytes) {
+        Some((width, height)) if width.max(height) > max => {
+            let resized = image::load_from_memory(bytes)?
+                .resize(max, max, image::imageops::FilterType::Lanczos3)
+                .to_rgb8();
+            info!(
+                "downscaled image from {}x{} to {}x{}",
+                width,
+                height,
+                resized.width(),
+                resized.height()
+            );
+            let mut jpeg = Vec::new();
+            image::DynamicImage::ImageRgb8(resized).write_to(
+                &mut std::io::Cursor::new(&mut jpeg),
+                image::ImageOutputFormat::Jpeg(90),
+            )?;
+            Ok(Some(jpeg))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// re-encodes an image from its pixels alone, dropping EXIF (including GPS)
+/// and any other metadata, after rotating it clockwise by `rotation` degrees
+/// as iProov would have, JPEGs stay JPEGs and other formats become PNGs
+pub fn strip_metadata(bytes: &[u8], rotation: u16) -> Result<Vec<u8>, EnrolError> {
+    let format = ImageFormat::detect(bytes).ok_or(EnrolError::UnknownImageFormat)?;
+    let image = image::load_from_memory(bytes)?;
+    let image = match rotation {
+        90 => image.rotate90(),
+        180 => image.rotate180(),
+        270 => image.rotate270(),
+        _ => image,
+    };
+    let mut stripped = Vec::new();
+    let mut output = std::io::Cursor::new(&mut stripped);
+    match format {
+        // JPEG has no alpha channel to keep
+        ImageFormat::Jpeg => image::DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_to(&mut output, image::ImageOutputFormat::Jpeg(95))?,
+        ImageFormat::Png | ImageFormat::Webp => {
+            image.write_to(&mut output, image::ImageOutputFormat::Png)?
+        }
+    }
+    info!(
+        "stripped image metadata, {} bytes before and {} after",
+        bytes.len(),
+        stripped.len()
+    );
+    Ok(stripped)
+}
+
+/// shortest side in pixels below which face capture is unlikely to succeed
+const MIN_DIMENSION: u32 = 480;
+
+/// rejects empty, oversized and unrecognised images before they are uploaded,
+/// and warns about images too small for a reliable face capture
+pub fn validate(bytes: &[u8], max_bytes: u64) -> Result<ImageFormat, EnrolError> {
+    check_size(bytes.len() as u64, max_bytes)?;
+    let format = ImageFormat::detect(bytes).ok_or(EnrolError::UnknownImageFormat)?;
+    check_dimensions(dimensions(bytes));
+    Ok(format)
+}
+
+fn check_size(size: u64, max_bytes: u64) -> Result<(), EnrolError> {
+    if size == 0 {
+        return Err(EnrolError::EmptyImage);
+    }
+    if size > max_bytes {
+        return Err(EnrolError::ImageTooLarge {
+            size,
+            max: max_bytes,
+        });
+    }
+    Ok(())
+}
+
+fn check_dimensions(dimensions: Option<(u32, u32)>) {
+    match dimensions {
+        Some((width, height)) if width.min(height) < MIN_DIMENSION => warn!(
+            "image is only {}x{}, at least {}px on the shortest side is recommended",
+            width, height, MIN_DIMENSION
+        ),
+        Some((width, height)) => debug!("image dimensions: {}x{}", width, height),
+        None => warn!("could not read the image dimensions"),
+    }
+}
+
+/// decodes a base64 image, optionally given as a data URL, ignoring any
+/// whitespace such as the line breaks of wrapped base64
+pub fn decode_base64(data: &str) -> Result<Vec<u8>, EnrolError> {
+    let data = match data.trim_start().strip_prefix("data:") {
+        Some(url) => url.split_once(',').map_or("", |(_, data)| data),
+        None => data,
+    };
+    let data = data
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect::<String>();
+    Ok(base64::engine::general_purpose::STANDARD.decode(data)?)
+}
+
+/// the JPEG, PNG and WebP files in `dir` sorted by name, identified by their
+/// magic bytes, anything else is skipped with a warning
+pub fn list_images(dir: &Path) -> Result<Vec<PathBuf>, EnrolError> {
+    let dir_error = |source| EnrolError::ImageDir {
+        path: dir.to_path_buf(),
+        source,
+    };
+    let mut paths = std::fs::read_dir(dir)
+        .map_err(dir_error)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(dir_error)?;
+    paths.sort();
+    paths.retain(|path| {
+        if !path.is_file() {
+            return false;
+        }
+        let mut magic = Vec::with_capacity(12);
+        let read = std::fs::File::open(path).and_then(|file| file.take(12).read_to_end(&mut magic));
+        match read {
+            Ok(_) if ImageFormat::detect(&magic).is_some() => true,
+            Ok(_) => {
+                warn!("skipping {}, not a JPEG, PNG or WebP image", path.display());
+                false
+            }
+            Err(err) => {
+                warn!("skipping {}, could not read it: {}", path.display(), err);
+                false
+            }
+        }
+    });
+    if paths.is_empty() {
+        return Err(EnrolError::NoImagesInDir(dir.to_path_buf()));
+    }
+    info!("found {} images in {}", paths.len(), dir.display());
+    Ok(paths)
+}
+
+/// reads the image at `path`, which may be an http(s) URL, or the whole of
+/// stdin when `path` is "-"
+pub async fn read_image(
+    client: &HttpClient,
+    args: &ImageArgs,
+    path: &str,
+) -> Result<Vec<u8>, EnrolError> {
+    if path == "-" {
+        let mut image = Vec::new();
+        tokio::io::stdin().read_to_end(&mut image).await?;
+        Ok(image)
+    } else if path.starts_with("http://") || path.starts_with("https://") {
+        download_image(client, args, path).await
+    } else {
+        Ok(tokio::fs::read(path).await?)
+    }
+}
+
+/// downloads the image at `url`, giving up as soon as it is known to be over
+/// --max-image-bytes, from its `Content-Length` or else as it arrives, even
+/// with --max-dimension since the whole download is held in memory
+pub async fn download_image(
+    client: &HttpClient,
+    args: &ImageArgs,
+    url: &str,
+) -> Result<Vec<u8>, EnrolError> {
+    if client.dry_run {
+        return Err(EnrolError::DryRunDownload(url.to_string()));
+    }
+    debug!("downloading image, url={}", url);
+    let mut res = client
+        .send("download image", |http| {
+            let request = http.get(url);
+            match args.download_timeout {
+                Some(secs) => request.timeout(Duration::from_secs(secs)),
+                None => request,
+            }
+        })
+        .await?;
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    if !content_type.starts_with("image/") {
+        return Err(EnrolError::NotAnImage {
+            url: url.to_string(),
+            content_type,
+        });
+    }
+    let max = args.max_image_bytes;
+    let too_large = || EnrolError::DownloadTooLarge {
+        url: url.to_string(),
+        max,
+    };
+    if res.content_length().is_some_and(|len| len > max) {
+        return Err(too_large());
+    }
+    let mut image = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        image.extend_from_slice(&chunk);
+        if image.len() as u64 > max {
+            return Err(too_large());
+        }
+    }
+    Ok(image)
+}
+
+/// rotation in degrees implied by the EXIF orientation tag, mirrored
+/// orientations are treated as their unmirrored equivalent
+pub fn exif_rotation(image: &[u8]) -> Option<u16> {
+    exif_rotation_from(std::io::Cursor::new(image))
+}
+
+/// like `exif_rotation`, only reading as far into `image` as the EXIF data
+fn exif_rotation_from<R: std::io::BufRead + std::io::Seek>(mut image: R) -> Option<u16> {
+    let exif = exif::Reader::new().read_from_container(&mut image).ok()?;
+    let orientation = exif
+        .get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+        .value
+        .get_uint(0)?;
+    match orientation {
+        1 | 2 => Some(0),
+        3 | 4 => Some(180),
+        5 | 6 => Some(90),
+        7 | 8 => Some(270),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a 4x2 JPEG carrying an EXIF orientation of 6, i.e. rotated 90 degrees
+    fn jpeg_with_orientation() -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        image::DynamicImage::ImageRgb8(image::RgbImage::new(4, 2))
+            .write_to(
+                &mut std::io::Cursor::new(&mut jpeg),
+                image::ImageOutputFormat::Jpeg(90),
+            )
+            .unwrap();
+        let mut tiff = b"MM\x00\x2a\x00\x00\x00\x08".to_vec();
+        tiff.extend([0x00, 0x01]);
+        tiff.extend([
+            0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01, 0x00, 0x06, 0x00, 0x00,
+        ]);
+        tiff.extend([0x00, 0x00, 0x00, 0x00]);
+        let mut app1 = b"Exif\x00\x00".to_vec();
+        app1.extend(tiff);
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend(((app1.len() + 2) as u16).to_be_bytes());
+        segment.extend(app1);
+        jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    #[test]
+    fn strip_metadata_drops_exif_and_applies_rotation() {
+        let jpeg = jpeg_with_orientation();
+        assert_eq!(exif_rotation(&jpeg), Some(90));
+
+        let stripped = strip_metadata(&jpeg, 90).unwrap();
+
+        assert_eq!(exif_rotation(&stripped), None);
+        assert!(matches!(
+            ImageFormat::detect(&stripped),
+            Some(ImageFormat::Jpeg)
+        ));
+        assert_eq!(dimensions(&stripped), Some((2, 4)));
+    }
+}
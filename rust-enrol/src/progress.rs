@@ -0,0 +1,30 @@
+use indicatif::{ProgressBar, ProgressStyle};
+
+use std::io::IsTerminal;
+
+use crate::cli::Args;
+
+/// whether --progress bars can be drawn, they are only shown on a terminal
+pub fn enabled(args: &Args) -> bool {
+    args.progress && std::io::stderr().is_terminal()
+}
+
+/// a bar for the upload of an image of `len` bytes
+pub fn upload_bar(len: u64) -> ProgressBar {
+    ProgressBar::new(len).with_style(
+        ProgressStyle::with_template("{msg} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec}")
+            .expect("progress template is valid")
+            .progress_chars("=> "),
+    )
+}
+
+/// a bar counting the completed enrolments of a batch of `count`
+pub fn batch_bar(count: u64) -> ProgressBar {
+    ProgressBar::new(count).with_style(
+        ProgressStyle::with_template(
+            "[{bar:30}] {pos}/{len} enrolments, {elapsed} elapsed, eta {eta}",
+        )
+        .expect("progress template is valid")
+        .progress_chars("=> "),
+    )
+}
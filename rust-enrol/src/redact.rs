@@ -0,0 +1,64 @@
+/// number of trailing characters left visible in a redacted secret
+const VISIBLE: usize = 4;
+
+/// what the hidden part of a secret is replaced with, the same whatever its
+/// length so that isn't given away either
+const MASK: &str = "****";
+
+/// masks all but the last few characters of a secret so logs can still tell
+/// credentials apart, short secrets are masked entirely
+pub fn redact(secret: &str) -> String {
+    let chars: Vec<char> = secret.chars().collect();
+    if chars.len() <= VISIBLE * 2 {
+        return MASK.to_string();
+    }
+    let visible: String = chars[chars.len() - VISIBLE..].iter().collect();
+    format!("{}{}", MASK, visible)
+}
+
+/// copy of a JSON object with the string values of `keys` redacted
+pub fn redact_json(value: &serde_json::Value, keys: &[&str]) -> serde_json::Value {
+    let mut value = value.clone();
+    for key in keys {
+        if let Some(secret) = value.get(*key).and_then(|secret| secret.as_str()) {
+            value[*key] = serde_json::Value::String(redact(secret));
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn long_secrets_keep_only_their_last_characters() {
+        assert_eq!(redact("0123456789abcdef"), "****cdef");
+        assert_eq!(redact("0123456789abcdef0123"), "****0123");
+    }
+
+    #[test]
+    fn short_secrets_are_masked_entirely() {
+        assert_eq!(redact("12345678"), "****");
+        assert_eq!(redact("1"), "****");
+        assert_eq!(redact(""), "****");
+    }
+
+    #[test]
+    fn redact_json_masks_only_the_given_keys() {
+        let value = json!({
+            "api_key": "api-key-0123456789",
+            "secret": "secret-0123456789",
+            "user_id": "test-user",
+            "rotation": 90,
+        });
+
+        let redacted = redact_json(&value, &["api_key", "secret", "rotation"]);
+        assert_eq!(redacted["api_key"], "****6789");
+        assert_eq!(redacted["secret"], "****6789");
+        assert_eq!(redacted["user_id"], "test-user");
+        assert_eq!(redacted["rotation"], 90);
+        assert!(!redacted.to_string().contains("api-key-0123456789"));
+    }
+}
@@ -0,0 +1,86 @@
+use std::process::Command;
+
+use crate::cli::{SecretsArgs, SecretsProvider};
+use crate::error::ConfigError;
+use crate::settings::PartialSettings;
+
+impl SecretsProvider {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Aws => "AWS Secrets Manager",
+            Self::Gcp => "GCP Secret Manager",
+        }
+    }
+
+    /// the latest value of the secret `name`, or `None` when there is no such
+    /// secret, read through the provider's CLI so that its credentials and
+    /// configuration are used as they are
+    pub fn fetch(self, name: &str) -> Result<Option<String>, ConfigError> {
+        let mut command = match self {
+            Self::Aws => {
+                let mut command = Command::new("aws");
+                command.args(["secretsmanager", "get-secret-value", "--secret-id", name]);
+                command.args(["--query", "SecretString", "--output", "text"]);
+                command
+            }
+            Self::Gcp => {
+                let mut command = Command::new("gcloud");
+                command.args(["secrets", "versions", "access", "latest"]);
+                command.arg(format!("--secret={}", name));
+                command
+            }
+        };
+        let error = |reason: String| ConfigError::SecretsProvider {
+            provider: self.name(),
+            name: name.to_string(),
+            reason,
+        };
+        let output = command.output().map_err(|err| error(err.to_string()))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if stderr.contains("ResourceNotFoundException") || stderr.contains("NOT_FOUND") {
+                return Ok(None);
+            }
+            return Err(error(stderr.trim().to_string()));
+        }
+        let secret = String::from_utf8_lossy(&output.stdout)
+            .trim_end_matches(['\r', '\n'])
+            .to_string();
+        Ok(Some(secret).filter(|secret| !secret.is_empty()))
+    }
+}
+
+/// sets `sp_secret` and `oa_pw` in `overrides` from the secrets named in
+/// `args`, any the provider doesn't have are left to the environment or config file
+pub fn fetch_secrets(
+    args: &SecretsArgs,
+    overrides: &mut PartialSettings,
+) -> Result<(), ConfigError> {
+    let provider = match args.secrets_provider {
+        Some(provider) => provider,
+        None => return Ok(()),
+    };
+    let secrets = [
+        ("sp_secret", &args.sp_secret_name, &mut overrides.sp_secret),
+        ("oa_pw", &args.oauth_pw_secret_name, &mut overrides.oa_pw),
+    ];
+    for (key, name, value) in secrets {
+        let name = match name {
+            Some(name) => name,
+            None => continue,
+        };
+        match provider.fetch(name)? {
+            Some(secret) => {
+                info!("{} read from {} secret {:?}", key, provider.name(), name);
+                *value = Some(secret);
+            }
+            None => warn!(
+                "{} has no secret {:?}, {} is read from the environment or config file",
+                provider.name(),
+                name,
+                key
+            ),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,1063 @@
+use serde::Deserialize;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::error::ConfigError;
+
+/// the iProov resource used for token requests when none is configured
+pub const DEFAULT_RESOURCE: &str = "photo_enrol_test";
+
+/// the `source` sent with images when none is configured
+pub const DEFAULT_IMAGE_SOURCE: &str = "selfie";
+
+/// the image sources iProov accepts unless `image_sources` says otherwise: the
+/// photo from an electronic ID's chip, a photo of an ID document, or a selfie
+pub const KNOWN_IMAGE_SOURCES: [&str; 3] = ["eid", "oid", "selfie"];
+
+/// iProov region codes and where they are hosted, as used in
+/// `https://{region}.secure.iproov.me`
+pub const KNOWN_REGIONS: [(&str, &str); 6] = [
+    ("eu", "Europe"),
+    ("eu.rp", "Europe, relying party platform"),
+    ("us", "United States"),
+    ("us.rp", "United States, relying party platform"),
+    ("au", "Australia"),
+    ("au.rp", "Australia, relying party platform"),
+];
+
+/// fields of the claim token request body that `meta` may not replace
+pub const RESERVED_META_KEYS: [&str; 6] = [
+    "api_key",
+    "secret",
+    "token",
+    "resource",
+    "user_id",
+    "assurance_type",
+];
+
+/// the multipart field names image uploads use for each logical field, the
+/// defaults are what iProov's v2 claim image endpoints expect
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct FieldNames {
+    pub image: String,
+    pub token: String,
+    pub source: String,
+    pub rotation: String,
+}
+
+impl Default for FieldNames {
+    fn default() -> Self {
+        Self {
+            image: "image".to_string(),
+            token: "token".to_string(),
+            source: "source".to_string(),
+            rotation: "rotation".to_string(),
+        }
+    }
+}
+
+impl FieldNames {
+    /// the logical fields `field_names` may rename
+    pub const LOGICAL: [&'static str; 4] = ["image", "token", "source", "rotation"];
+
+    /// the defaults with `overrides`, keyed by logical field, applied on top
+    fn with_overrides(overrides: &BTreeMap<String, String>) -> Result<Self, ConfigError> {
+        let mut names = Self::default();
+        for (field, name) in overrides {
+            let slot = match field.as_str() {
+                "image" => &mut names.image,
+                "token" => &mut names.token,
+                "source" => &mut names.source,
+                "rotation" => &mut names.rotation,
+                _ => {
+                    return Err(ConfigError::UnknownFormField {
+                        field: field.clone(),
+                        known: Self::LOGICAL.join(", "),
+                    })
+                }
+            };
+            if name.is_empty() {
+                return Err(ConfigError::Empty("field_names values"));
+            }
+            *slot = name.clone();
+        }
+        // api_key and secret are always sent under their own names
+        let mut sent = vec!["api_key", "secret"];
+        for name in [&names.image, &names.token, &names.source, &names.rotation] {
+            if sent.contains(&name.as_str()) {
+                return Err(ConfigError::DuplicateFormField(name.clone()));
+            }
+            sent.push(name);
+        }
+        Ok(names)
+    }
+}
+
+/// the OAuth grant access tokens are requested with unless `oauth_grant_type` says otherwise
+pub const DEFAULT_GRANT_TYPE: &str = "client_credentials";
+
+/// the form fields `oauth_fields` has to provide for the grants that need more
+/// than the client credentials sent as basic auth
+pub const GRANT_FIELDS: [(&str, &[&str]); 1] = [("password", &["username", "password"])];
+
+/// an image and the source it is sent with, see `Settings::images`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ImageSource {
+    pub path: String,
+    pub source: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Settings {
+    pub region: String,
+    pub img_src: String,
+    pub img_path: Option<String>,
+    pub sp_key: String,
+    pub sp_secret: String,
+    pub oa_username: String,
+    pub oa_pw: String,
+    pub resource: String,
+    pub base_url: Option<String>,
+    /// the region of the enrol and verify claim calls, token and image, in place of `region`
+    pub token_region: Option<String>,
+    /// the region of user deletions, and the access tokens they are made with, in place of `region`
+    pub delete_region: Option<String>,
+    /// the OAuth token endpoint, used as is in place of the one derived from `base_url`
+    pub oauth_url: Option<String>,
+    /// the `grant_type` of access token requests
+    pub oauth_grant_type: String,
+    /// extra form fields of access token requests, as the grant type requires
+    pub oauth_fields: BTreeMap<String, String>,
+    /// images cycled through by batch enrolments in place of `img_path`/`img_src`
+    pub images: Vec<ImageSource>,
+    /// sent as `assurance_type` in claim token requests
+    pub assurance_type: Option<String>,
+    /// extra fields merged into claim token requests
+    pub meta: BTreeMap<String, String>,
+    /// the names image uploads send their fields under
+    pub field_names: FieldNames,
+    /// the sources images may be sent with, `KNOWN_IMAGE_SOURCES` unless configured
+    pub image_sources: Vec<String>,
+}
+
+impl Settings {
+    /// settings given in code rather than read from the environment or a
+    /// file, `PartialSettings::build` checks the required ones were set
+    pub fn builder() -> PartialSettings {
+        PartialSettings::default()
+    }
+
+    /// the image path and source for the `index`th enrolment, wrapping around
+    /// `images` when it is set
+    pub fn image_source(&self, index: usize) -> (Option<&str>, &str) {
+        if self.images.is_empty() {
+            (self.img_path.as_deref(), &self.img_src)
+        } else {
+            let image = &self.images[index % self.images.len()];
+            (Some(&image.path), &image.source)
+        }
+    }
+
+    /// rejects a source that isn't one of `image_sources`
+    pub fn check_image_source(&self, source: &str) -> Result<(), ConfigError> {
+        if self.image_sources.iter().any(|allowed| allowed == source) {
+            return Ok(());
+        }
+        Err(ConfigError::UnknownImageSource {
+            image_source: source.to_string(),
+            allowed: self.image_sources.join(", "),
+        })
+    }
+
+    /// scheme and host every endpoint is built on, `base_url` replaces the
+    /// region derived SaaS host for self-hosted or staging deployments
+    pub fn base_url(&self) -> String {
+        self.base_url_in(None)
+    }
+
+    /// `base_url`, or the SaaS host of `region` for a call overriding the
+    /// region, e.g. with `token_region`
+    pub fn base_url_in(&self, region: Option<&str>) -> String {
+        match (region, &self.base_url) {
+            (Some(region), _) => format!("https://{}.secure.iproov.me", region),
+            (None, Some(base_url)) => base_url.trim_end_matches('/').to_string(),
+            (None, None) => format!("https://{}.secure.iproov.me", self.region),
+        }
+    }
+
+    /// the region tokens are claimed and images enrolled in, `token_region`
+    /// when it overrides the configured region
+    pub fn enrol_region(&self) -> &str {
+        self.token_region.as_deref().unwrap_or(&self.region)
+    }
+
+    /// the region users are deleted from, `delete_region` when it overrides
+    /// the configured region
+    pub fn deletion_region(&self) -> &str {
+        self.delete_region.as_deref().unwrap_or(&self.region)
+    }
+
+    /// checks the region, and any per-call region, is one of `KNOWN_REGIONS`,
+    /// catching typos before they turn into DNS failures, the region is unused
+    /// when `base_url` is set
+    pub fn validate_region(&self) -> Result<(), ConfigError> {
+        if self.base_url.is_some() {
+            return Ok(());
+        }
+        let regions = std::iter::once(&self.region)
+            .chain(&self.token_region)
+            .chain(&self.delete_region);
+        for region in regions {
+            if !KNOWN_REGIONS.iter().any(|(code, _)| code == region) {
+                return Err(ConfigError::UnknownRegion {
+                    region: region.clone(),
+                    known: KNOWN_REGIONS
+                        .iter()
+                        .map(|(code, _)| *code)
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn from_env() -> Result<Self, ConfigError> {
+        PartialSettings::from_env()?.build()
+    }
+
+    pub fn from_file(path: &Path, profile: Option<&str>) -> Result<Self, ConfigError> {
+        PartialSettings::from_file(path, profile)?.build()
+    }
+
+    /// loads settings from the given config file and profile with environment
+    /// overrides, falling back to `.env`/environment only when no file is given,
+    /// then applies `overrides` (typically from the command line) on top
+    pub fn load(
+        path: Option<&Path>,
+        profile: Option<&str>,
+        overrides: PartialSettings,
+    ) -> Result<Self, ConfigError> {
+        let settings = match (path, profile) {
+            (Some(path), profile) => {
+                PartialSettings::from_file(path, profile)?.merge(PartialSettings::from_env()?)
+            }
+            (None, Some(_)) => return Err(ConfigError::ProfileWithoutFile),
+            (None, None) => PartialSettings::from_env()?,
+        };
+        settings.merge(overrides).build()
+    }
+}
+
+/// reads `var`, or the file named by `{var}_FILE` when that is set so secrets
+/// can be mounted as files (e.g. Docker secrets) rather than passed in the
+/// environment, an empty secret is treated as unset
+fn secret_var(var: &str) -> Result<Option<String>, ConfigError> {
+    let file_var = format!("{}_FILE", var);
+    match std::env::var(&file_var) {
+        Ok(path) => match std::fs::read_to_string(&path) {
+            Ok(secret) => Ok(Some(secret.trim_end_matches(['\r', '\n']).to_string())
+                .filter(|secret| !secret.is_empty())),
+            Err(source) => Err(ConfigError::SecretFile {
+                var: file_var,
+                path,
+                source,
+            }),
+        },
+        Err(_) => Ok(std::env::var(var).ok().filter(|secret| !secret.is_empty())),
+    }
+}
+
+/// keys of a config file that aren't settings
+const FILE_KEYS: [&str; 2] = ["default_profile", "profiles"];
+
+/// rejects keys of the top level or a profile of `file` that no setting is
+/// read from, which would otherwise be ignored, e.g. a misspelt `regon`
+fn check_keys(file: &config::Config) -> Result<(), ConfigError> {
+    let settings = serde_fields::<PartialSettings>();
+    let top_level = file
+        .clone()
+        .try_deserialize::<BTreeMap<String, config::Value>>()?;
+    let known = settings
+        .iter()
+        .chain(&FILE_KEYS)
+        .copied()
+        .collect::<Vec<_>>();
+    for key in top_level.keys() {
+        check_key(key, key, &known)?;
+    }
+    if let Some(profiles) = top_level.get("profiles") {
+        for (name, profile) in profiles.clone().into_table()? {
+            for key in profile.into_table()?.keys() {
+                check_key(key, &format!("profiles.{}.{}", name, key), settings)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_key(key: &str, path: &str, known: &[&str]) -> Result<(), ConfigError> {
+    if known.contains(&key) {
+        return Ok(());
+    }
+    let suggestion = known
+        .iter()
+        .map(|candidate| (edit_distance(key, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .min()
+        .map(|(_, candidate)| candidate.to_string());
+    Err(ConfigError::UnknownKey {
+        key: path.to_string(),
+        suggestion,
+    })
+}
+
+/// replaces `${VAR}` in the strings of `value`, found at `key`, with the
+/// environment variable `VAR`, and `$$` with a literal `$`
+fn interpolate(value: &mut config::Value, key: &str) -> Result<(), ConfigError> {
+    let nested = |name: &str| match key {
+        "" => name.to_string(),
+        key => format!("{}.{}", key, name),
+    };
+    match &mut value.kind {
+        config::ValueKind::String(text) => *text = interpolate_str(text, key)?,
+        config::ValueKind::Table(table) => {
+            for (name, value) in table.iter_mut() {
+                interpolate(value, &nested(name))?;
+            }
+        }
+        config::ValueKind::Array(values) => {
+            for (index, value) in values.iter_mut().enumerate() {
+                interpolate(value, &format!("{}[{}]", key, index))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn interpolate_str(text: &str, key: &str) -> Result<String, ConfigError> {
+    let mut interpolated = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(dollar) = rest.find('$') {
+        interpolated.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            interpolated.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let (var, after) = after
+                .split_once('}')
+                .ok_or_else(|| ConfigError::Interpolation {
+                    key: key.to_string(),
+                    reason: "unterminated ${, write $$ for a literal $".to_string(),
+                })?;
+            let value = std::env::var(var).map_err(|_| ConfigError::Interpolation {
+                key: key.to_string(),
+                reason: format!("environment variable {:?} is not set", var),
+            })?;
+            interpolated.push_str(&value);
+            rest = after;
+        } else {
+            interpolated.push('$');
+        }
+    }
+    interpolated.push_str(rest);
+    Ok(interpolated)
+}
+
+/// the furthest a misspelt key may be from a valid one for it to be suggested
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+/// the Levenshtein distance between `a` and `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// the field names serde reads `T` from, found by having `T` deserialize from
+/// a deserializer that records the fields it is asked for
+fn serde_fields<T: serde::de::DeserializeOwned>() -> &'static [&'static str] {
+    use serde::de::{self, Visitor};
+
+    struct Fields<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> de::Deserializer<'de> for Fields<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("only structs have fields"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("fields recorded"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+            byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map enum
+            identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    T::deserialize(Fields(&mut fields)).ok();
+    fields
+}
+
+/// the entries of both maps, `other`'s taking precedence
+fn merge_maps(
+    map: Option<BTreeMap<String, String>>,
+    other: Option<BTreeMap<String, String>>,
+) -> Option<BTreeMap<String, String>> {
+    match (map, other) {
+        (Some(mut map), Some(other)) => {
+            map.extend(other);
+            Some(map)
+        }
+        (map, other) => other.or(map),
+    }
+}
+
+/// a config file, whose top-level settings are shared by every profile in `profiles`
+#[derive(Deserialize, Debug)]
+struct ConfigFile {
+    #[serde(flatten)]
+    settings: PartialSettings,
+    /// the profile used when `--profile` isn't given
+    default_profile: Option<String>,
+    #[serde(default)]
+    profiles: BTreeMap<String, PartialSettings>,
+}
+
+/// settings as read from a single source, where any value may be absent, also
+/// the builder returned by `Settings::builder`
+#[derive(Deserialize, Debug, Default)]
+pub struct PartialSettings {
+    pub region: Option<String>,
+    pub img_src: Option<String>,
+    pub img_path: Option<String>,
+    pub sp_key: Option<String>,
+    pub sp_secret: Option<String>,
+    pub oa_username: Option<String>,
+    pub oa_pw: Option<String>,
+    pub resource: Option<String>,
+    pub base_url: Option<String>,
+    pub token_region: Option<String>,
+    pub delete_region: Option<String>,
+    pub oauth_url: Option<String>,
+    pub oauth_grant_type: Option<String>,
+    pub oauth_fields: Option<BTreeMap<String, String>>,
+    pub images: Option<Vec<ImageSource>>,
+    pub assurance_type: Option<String>,
+    pub meta: Option<BTreeMap<String, String>>,
+    /// renames of the image upload fields, keyed by logical field, see `FieldNames`
+    pub field_names: Option<BTreeMap<String, String>>,
+    /// the image sources a deployment accepts, in place of `KNOWN_IMAGE_SOURCES`
+    pub image_sources: Option<Vec<String>>,
+}
+
+impl PartialSettings {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        dotenv::dotenv().ok();
+        Ok(Self {
+            region: std::env::var("REGION")
+                .ok()
+                .filter(|region| !region.is_empty()),
+            img_src: std::env::var("IMAGE_SOURCE").ok(),
+            img_path: std::env::var("IMAGE_PATH")
+                .ok()
+                .filter(|path| !path.is_empty()),
+            sp_key: secret_var("SP_KEY")?,
+            sp_secret: secret_var("SP_SECRET")?,
+            oa_username: secret_var("OAUTH_USERNAME")?,
+            oa_pw: secret_var("OAUTH_PW")?,
+            resource: std::env::var("RESOURCE").ok(),
+            base_url: std::env::var("BASE_URL").ok().filter(|url| !url.is_empty()),
+            token_region: std::env::var("TOKEN_REGION")
+                .ok()
+                .filter(|region| !region.is_empty()),
+            delete_region: std::env::var("DELETE_REGION")
+                .ok()
+                .filter(|region| !region.is_empty()),
+            oauth_url: std::env::var("OAUTH_URL")
+                .ok()
+                .filter(|url| !url.is_empty()),
+            oauth_grant_type: std::env::var("OAUTH_GRANT_TYPE")
+                .ok()
+                .filter(|grant| !grant.is_empty()),
+            oauth_fields: None,
+            images: None,
+            assurance_type: std::env::var("ASSURANCE_TYPE").ok(),
+            meta: None,
+            field_names: None,
+            image_sources: std::env::var("IMAGE_SOURCES").ok().map(|sources| {
+                sources
+                    .split(',')
+                    .map(|source| source.trim().to_string())
+                    .filter(|source| !source.is_empty())
+                    .collect()
+            }),
+        })
+    }
+
+    /// the top-level settings of the file at `path` overlaid with those of
+    /// `profile`, or of its `default_profile` when no profile is given, with
+    /// `${VAR}` in its values replaced by the environment variable
+    pub fn from_file(path: &Path, profile: Option<&str>) -> Result<Self, ConfigError> {
+        let file = config::Config::builder()
+            .add_source(config::File::from(path).format(config::FileFormat::Toml))
+            .build()?;
+        check_keys(&file)?;
+        dotenv::dotenv().ok();
+        let mut values: config::Value = file.try_deserialize()?;
+        interpolate(&mut values, "")?;
+        let mut file = ConfigFile::deserialize(values)?;
+        let profile = match profile.map(str::to_string).or(file.default_profile) {
+            Some(profile) => profile,
+            None => return Ok(file.settings),
+        };
+        match file.profiles.remove(&profile) {
+            Some(selected) => Ok(file.settings.merge(selected)),
+            None => Err(ConfigError::UnknownProfile {
+                profile,
+                known: file.profiles.into_keys().collect::<Vec<_>>().join(", "),
+            }),
+        }
+    }
+
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.region = Some(region.into());
+        self
+    }
+
+    pub fn img_src(mut self, img_src: impl Into<String>) -> Self {
+        self.img_src = Some(img_src.into());
+        self
+    }
+
+    pub fn img_path(mut self, img_path: impl Into<String>) -> Self {
+        self.img_path = Some(img_path.into());
+        self
+    }
+
+    pub fn sp_key(mut self, sp_key: impl Into<String>) -> Self {
+        self.sp_key = Some(sp_key.into());
+        self
+    }
+
+    pub fn sp_secret(mut self, sp_secret: impl Into<String>) -> Self {
+        self.sp_secret = Some(sp_secret.into());
+        self
+    }
+
+    pub fn oa_username(mut self, oa_username: impl Into<String>) -> Self {
+        self.oa_username = Some(oa_username.into());
+        self
+    }
+
+    pub fn oa_pw(mut self, oa_pw: impl Into<String>) -> Self {
+        self.oa_pw = Some(oa_pw.into());
+        self
+    }
+
+    pub fn resource(mut self, resource: impl Into<String>) -> Self {
+        self.resource = Some(resource.into());
+        self
+    }
+
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+
+    pub fn token_region(mut self, token_region: impl Into<String>) -> Self {
+        self.token_region = Some(token_region.into());
+        self
+    }
+
+    pub fn delete_region(mut self, delete_region: impl Into<String>) -> Self {
+        self.delete_region = Some(delete_region.into());
+        self
+    }
+
+    pub fn oauth_url(mut self, oauth_url: impl Into<String>) -> Self {
+        self.oauth_url = Some(oauth_url.into());
+        self
+    }
+
+    pub fn oauth_grant_type(mut self, oauth_grant_type: impl Into<String>) -> Self {
+        self.oauth_grant_type = Some(oauth_grant_type.into());
+        self
+    }
+
+    /// adds a field to `oauth_fields`
+    pub fn oauth_field(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.oauth_fields
+            .get_or_insert_with(BTreeMap::new)
+            .insert(key.into(), value.into());
+        self
+    }
+
+    /// adds an image to `images`
+    pub fn image(mut self, path: impl Into<String>, source: impl Into<String>) -> Self {
+        self.images.get_or_insert_with(Vec::new).push(ImageSource {
+            path: path.into(),
+            source: source.into(),
+        });
+        self
+    }
+
+    pub fn assurance_type(mut self, assurance_type: impl Into<String>) -> Self {
+        self.assurance_type = Some(assurance_type.into());
+        self
+    }
+
+    /// adds a field to `meta`
+    pub fn meta(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.meta
+            .get_or_insert_with(BTreeMap::new)
+            .insert(key.into(), value.into());
+        self
+    }
+
+    /// sends the logical upload `field` as `name`, see `FieldNames`
+    pub fn field_name(mut self, field: impl Into<String>, name: impl Into<String>) -> Self {
+        self.field_names
+            .get_or_insert_with(BTreeMap::new)
+            .insert(field.into(), name.into());
+        self
+    }
+
+    /// adds a source to those `image_sources` accepts
+    pub fn image_source(mut self, source: impl Into<String>) -> Self {
+        self.image_sources
+            .get_or_insert_with(Vec::new)
+            .push(source.into());
+        self
+    }
+
+    /// values present in `other` take precedence over those in `self`, `meta`,
+    /// `oauth_fields` and `field_names` are merged key by key
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            region: other.region.or(self.region),
+            img_src: other.img_src.or(self.img_src),
+            img_path: other.img_path.or(self.img_path),
+            sp_key: other.sp_key.or(self.sp_key),
+            sp_secret: other.sp_secret.or(self.sp_secret),
+            oa_username: other.oa_username.or(self.oa_username),
+            oa_pw: other.oa_pw.or(self.oa_pw),
+            resource: other.resource.or(self.resource),
+            base_url: other.base_url.or(self.base_url),
+            token_region: other.token_region.or(self.token_region),
+            delete_region: other.delete_region.or(self.delete_region),
+            oauth_url: other.oauth_url.or(self.oauth_url),
+            oauth_grant_type: other.oauth_grant_type.or(self.oauth_grant_type),
+            oauth_fields: merge_maps(self.oauth_fields, other.oauth_fields),
+            images: other.images.or(self.images),
+            assurance_type: other.assurance_type.or(self.assurance_type),
+            meta: merge_maps(self.meta, other.meta),
+            field_names: merge_maps(self.field_names, other.field_names),
+            image_sources: other.image_sources.or(self.image_sources),
+        }
+    }
+
+    pub fn build(self) -> Result<Settings, ConfigError> {
+        let mut missing = Vec::new();
+        // an empty value, e.g. left blank in the `.env_template`, is as good as missing
+        let mut require = |value: Option<String>, key: &str, var: &str| {
+            value.filter(|value| !value.is_empty()).unwrap_or_else(|| {
+                missing.push(format!("{} ({})", key, var));
+                String::new()
+            })
+        };
+        let settings = Settings {
+            region: require(self.region, "region", "REGION"),
+            img_src: self
+                .img_src
+                .unwrap_or_else(|| DEFAULT_IMAGE_SOURCE.to_string()),
+            img_path: self.img_path,
+            sp_key: require(self.sp_key, "sp_key", "SP_KEY"),
+            sp_secret: require(self.sp_secret, "sp_secret", "SP_SECRET"),
+            oa_username: require(self.oa_username, "oa_username", "OAUTH_USERNAME"),
+            oa_pw: require(self.oa_pw, "oa_pw", "OAUTH_PW"),
+            resource: self
+                .resource
+                .unwrap_or_else(|| DEFAULT_RESOURCE.to_string()),
+            base_url: self.base_url,
+            token_region: self.token_region,
+            delete_region: self.delete_region,
+            oauth_url: self.oauth_url,
+            oauth_grant_type: self
+                .oauth_grant_type
+                .unwrap_or_else(|| DEFAULT_GRANT_TYPE.to_string()),
+            oauth_fields: self.oauth_fields.unwrap_or_default(),
+            images: self.images.unwrap_or_default(),
+            assurance_type: self.assurance_type,
+            meta: self.meta.unwrap_or_default(),
+            field_names: FieldNames::with_overrides(&self.field_names.unwrap_or_default())?,
+            image_sources: match self.image_sources {
+                Some(sources) if !sources.is_empty() => sources,
+                _ => KNOWN_IMAGE_SOURCES.map(str::to_string).to_vec(),
+            },
+        };
+        if !missing.is_empty() {
+            return Err(ConfigError::Missing(missing));
+        }
+        if settings.resource.is_empty() {
+            return Err(ConfigError::Empty("resource (RESOURCE)"));
+        }
+        if let Some(key) = settings
+            .meta
+            .keys()
+            .find(|key| RESERVED_META_KEYS.contains(&key.as_str()))
+        {
+            return Err(ConfigError::ReservedMeta(key.clone()));
+        }
+        if settings.base_url.is_some()
+            && (settings.token_region.is_some() || settings.delete_region.is_some())
+        {
+            return Err(ConfigError::RegionWithBaseUrl);
+        }
+        if settings.oauth_fields.contains_key("grant_type") {
+            return Err(ConfigError::ReservedOAuthField);
+        }
+        let required = GRANT_FIELDS
+            .iter()
+            .find(|(grant, _)| *grant == settings.oauth_grant_type)
+            .map_or(&[][..], |(_, fields)| fields);
+        if let Some(field) = required
+            .iter()
+            .find(|field| !settings.oauth_fields.contains_key(**field))
+        {
+            return Err(ConfigError::MissingGrantField {
+                grant_type: settings.oauth_grant_type.clone(),
+                field: field.to_string(),
+            });
+        }
+        for source in std::iter::once(&settings.img_src)
+            .chain(settings.images.iter().map(|image| &image.source))
+        {
+            settings.check_image_source(source)?;
+        }
+        if let Some(image) = settings.images.iter().find(|image| {
+            !image.path.starts_with("http://")
+                && !image.path.starts_with("https://")
+                && !Path::new(&image.path).is_file()
+        }) {
+            return Err(ConfigError::MissingImage(image.path.clone()));
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_file(contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("rust-enrol-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    const PROFILES: &str = r#"
+region = "eu"
+sp_key = "shared"
+default_profile = "dev"
+
+[meta]
+team = "qa"
+
+[profiles.dev]
+sp_key = "dev-key"
+
+[profiles.prod]
+region = "us"
+sp_key = "prod-key"
+
+[profiles.prod.meta]
+env = "prod"
+"#;
+
+    #[test]
+    fn profile_overlays_top_level_settings() {
+        let path = config_file(PROFILES);
+
+        let prod = PartialSettings::from_file(&path, Some("prod")).unwrap();
+        assert_eq!(prod.region.as_deref(), Some("us"));
+        assert_eq!(prod.sp_key.as_deref(), Some("prod-key"));
+        assert_eq!(
+            prod.meta.unwrap(),
+            BTreeMap::from([
+                ("env".to_string(), "prod".to_string()),
+                ("team".to_string(), "qa".to_string())
+            ])
+        );
+
+        let default = PartialSettings::from_file(&path, None).unwrap();
+        assert_eq!(default.region.as_deref(), Some("eu"));
+        assert_eq!(default.sp_key.as_deref(), Some("dev-key"));
+
+        let unknown = PartialSettings::from_file(&path, Some("stage")).unwrap_err();
+        assert_eq!(
+            unknown.to_string(),
+            "unknown profile \"stage\", the config file defines: dev, prod"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn credentials() -> PartialSettings {
+        PartialSettings {
+            region: Some("eu".to_string()),
+            sp_key: Some("key".to_string()),
+            sp_secret: Some("secret".to_string()),
+            oa_username: Some("username".to_string()),
+            oa_pw: Some("password".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn image_source_defaults_and_is_checked_against_the_allowlist() {
+        assert_eq!(credentials().build().unwrap().img_src, DEFAULT_IMAGE_SOURCE);
+
+        let unknown = PartialSettings {
+            img_src: Some("passport".to_string()),
+            ..credentials()
+        };
+        assert!(matches!(
+            unknown.build(),
+            Err(ConfigError::UnknownImageSource { image_source, .. }) if image_source == "passport"
+        ));
+
+        let allowed = PartialSettings {
+            img_src: Some("passport".to_string()),
+            image_sources: Some(vec!["passport".to_string()]),
+            ..credentials()
+        };
+        assert_eq!(allowed.build().unwrap().img_src, "passport");
+    }
+
+    #[test]
+    fn region_overrides_replace_the_region_per_call() {
+        let settings = credentials().build().unwrap();
+        assert_eq!(settings.enrol_region(), "eu");
+        assert_eq!(settings.deletion_region(), "eu");
+
+        let overridden = Settings::builder()
+            .region("eu")
+            .token_region("us")
+            .delete_region("sg")
+            .sp_key("key")
+            .sp_secret("secret")
+            .oa_username("username")
+            .oa_pw("password")
+            .build()
+            .unwrap();
+        assert_eq!(overridden.enrol_region(), "us");
+        assert_eq!(overridden.deletion_region(), "sg");
+        assert_eq!(
+            overridden.base_url_in(overridden.token_region.as_deref()),
+            "https://us.secure.iproov.me"
+        );
+    }
+
+    #[test]
+    fn field_names_rename_the_upload_fields() {
+        let renamed = PartialSettings {
+            field_names: Some(BTreeMap::from([("image".to_string(), "file".to_string())])),
+            ..credentials()
+        };
+        assert_eq!(
+            renamed.build().unwrap().field_names,
+            FieldNames {
+                image: "file".to_string(),
+                ..FieldNames::default()
+            }
+        );
+
+        let unknown = PartialSettings {
+            field_names: Some(BTreeMap::from([("photo".to_string(), "file".to_string())])),
+            ..credentials()
+        };
+        assert!(matches!(
+            unknown.build(),
+            Err(ConfigError::UnknownFormField { field, .. }) if field == "photo"
+        ));
+
+        let clash = PartialSettings {
+            field_names: Some(BTreeMap::from([(
+                "source".to_string(),
+                "token".to_string(),
+            )])),
+            ..credentials()
+        };
+        assert!(matches!(
+            clash.build(),
+            Err(ConfigError::DuplicateFormField(name)) if name == "token"
+        ));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected_with_a_suggestion() {
+        let path = config_file("regon = \"eu\"\n");
+        let err = PartialSettings::from_file(&path, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown key \"regon\" in the config file, did you mean \"region\"?"
+        );
+        std::fs::remove_file(path).unwrap();
+
+        let path = config_file("[profiles.dev]\nsp_key = \"key\"\ncolour = \"blue\"\n");
+        let err = PartialSettings::from_file(&path, Some("dev")).unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::UnknownKey { key, suggestion: None } if key == "profiles.dev.colour"
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn builder_checks_required_settings() {
+        let settings = Settings::builder()
+            .region("eu")
+            .sp_key("key")
+            .sp_secret("secret")
+            .oa_username("username")
+            .oa_pw("password")
+            .meta("team", "qa")
+            .build()
+            .unwrap();
+        assert_eq!(settings.region, "eu");
+        assert_eq!(settings.resource, DEFAULT_RESOURCE);
+        assert_eq!(settings.meta["team"], "qa");
+
+        let missing = Settings::builder().region("eu").sp_key("key").build();
+        assert_eq!(
+            missing.unwrap_err().to_string(),
+            "missing configuration values: sp_secret (SP_SECRET), oa_username (OAUTH_USERNAME), \
+             oa_pw (OAUTH_PW)"
+        );
+    }
+
+    #[test]
+    fn blank_credentials_are_missing() {
+        let blank = PartialSettings {
+            sp_key: Some(String::new()),
+            sp_secret: Some(String::new()),
+            oa_username: Some(String::new()),
+            oa_pw: Some(String::new()),
+            ..credentials()
+        };
+        match blank.build() {
+            Err(ConfigError::Missing(missing)) => assert_eq!(
+                missing,
+                vec![
+                    "sp_key (SP_KEY)",
+                    "sp_secret (SP_SECRET)",
+                    "oa_username (OAUTH_USERNAME)",
+                    "oa_pw (OAUTH_PW)",
+                ]
+            ),
+            other => panic!("expected missing credentials, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn password_grant_needs_its_fields() {
+        let client_credentials = credentials().build().unwrap();
+        assert_eq!(client_credentials.oauth_grant_type, DEFAULT_GRANT_TYPE);
+
+        let missing = credentials()
+            .oauth_grant_type("password")
+            .oauth_field("username", "operator")
+            .build();
+        assert!(matches!(
+            missing,
+            Err(ConfigError::MissingGrantField { field, .. }) if field == "password"
+        ));
+
+        let password = credentials()
+            .oauth_grant_type("password")
+            .oauth_field("username", "operator")
+            .oauth_field("password", "hunter2")
+            .build();
+        assert!(password.is_ok());
+    }
+
+    #[test]
+    fn values_interpolate_environment_variables() {
+        std::env::set_var("RUST_ENROL_TEST_SECRET", "from-env");
+        let path = config_file(
+            "sp_secret = \"${RUST_ENROL_TEST_SECRET}\"\nsp_key = \"$$literal\"\n\
+             [profiles.dev]\noa_pw = \"pre-${RUST_ENROL_TEST_SECRET}\"\n",
+        );
+        let settings = PartialSettings::from_file(&path, Some("dev")).unwrap();
+        assert_eq!(settings.sp_secret.as_deref(), Some("from-env"));
+        assert_eq!(settings.sp_key.as_deref(), Some("$literal"));
+        assert_eq!(settings.oa_pw.as_deref(), Some("pre-from-env"));
+        std::fs::remove_file(path).unwrap();
+
+        let path = config_file("sp_secret = \"${RUST_ENROL_TEST_UNSET}\"\n");
+        let err = PartialSettings::from_file(&path, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "could not interpolate \"sp_secret\" in the config file: environment variable \
+             \"RUST_ENROL_TEST_UNSET\" is not set"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_without_profiles_uses_top_level_settings() {
+        let path = config_file("region = \"eu\"\n");
+        let settings = PartialSettings::from_file(&path, None).unwrap();
+        assert_eq!(settings.region.as_deref(), Some("eu"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn per_call_regions_fall_back_to_the_region() {
+        let settings = credentials().delete_region("us").build().unwrap();
+        assert_eq!(
+            settings.base_url_in(settings.token_region.as_deref()),
+            "https://eu.secure.iproov.me"
+        );
+        assert_eq!(
+            settings.base_url_in(settings.delete_region.as_deref()),
+            "https://us.secure.iproov.me"
+        );
+
+        let unknown = credentials().token_region("eu.pr").build().unwrap();
+        assert!(matches!(
+            unknown.validate_region(),
+            Err(ConfigError::UnknownRegion { region, .. }) if region == "eu.pr"
+        ));
+
+        let err = credentials()
+            .base_url("https://iproov.example.com")
+            .token_region("us")
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::RegionWithBaseUrl));
+    }
+}
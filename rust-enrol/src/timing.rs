@@ -0,0 +1,70 @@
+use serde_json::json;
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// how long each phase of an enrolment took, in the order they ran
+#[derive(Default, Debug, Clone)]
+pub struct Timings(Vec<(&'static str, Duration)>);
+
+impl Timings {
+    /// awaits `operation`, logging and recording how long it took as `phase`
+    pub async fn time<F: Future>(&mut self, phase: &'static str, operation: F) -> F::Output {
+        let started = Instant::now();
+        let output = operation.await;
+        let elapsed = started.elapsed();
+        debug!("{} took {:.0}ms", phase, millis(elapsed));
+        self.0.push((phase, elapsed));
+        output
+    }
+
+    /// the phases as `{"<phase>_ms": <milliseconds>}`
+    pub fn to_json(&self) -> serde_json::Value {
+        let phases = self
+            .0
+            .iter()
+            .map(|(phase, elapsed)| (format!("{}_ms", phase), json!(elapsed.as_millis() as u64)))
+            .collect::<serde_json::Map<_, _>>();
+        serde_json::Value::Object(phases)
+    }
+}
+
+/// serialized as `to_json`
+impl serde::Serialize for Timings {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_json().serialize(serializer)
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// logs the min, median and 95th percentile of each phase across `timings`,
+/// phases are listed in the order they first appear
+pub fn log_summary(timings: &[Timings]) {
+    let mut phases: Vec<(&str, Vec<Duration>)> = Vec::new();
+    for (phase, elapsed) in timings.iter().flat_map(|timings| &timings.0) {
+        match phases.iter_mut().find(|(name, _)| name == phase) {
+            Some((_, durations)) => durations.push(*elapsed),
+            None => phases.push((phase, vec![*elapsed])),
+        }
+    }
+    for (phase, mut durations) in phases {
+        durations.sort();
+        info!(
+            "{}: min={:.0}ms median={:.0}ms p95={:.0}ms over {} calls",
+            phase,
+            millis(durations[0]),
+            millis(percentile(&durations, 50)),
+            millis(percentile(&durations, 95)),
+            durations.len()
+        );
+    }
+}
+
+/// the nearest-rank percentile of non-empty, sorted `durations`
+fn percentile(durations: &[Duration], percent: usize) -> Duration {
+    let rank = (durations.len() * percent).div_ceil(100);
+    durations[rank.saturating_sub(1)]
+}